@@ -1,24 +1,44 @@
 from __future__ import annotations
 
 import asyncio
+import json
+import re
 from asyncio import Semaphore
-from datetime import datetime
+from collections import Counter
+from contextlib import contextmanager, nullcontext
+from datetime import datetime, timedelta, timezone
 from enum import Enum
 from fnmatch import fnmatch
+from os import environ
+from pathlib import Path
 from sys import argv
-from typing import TYPE_CHECKING, NamedTuple
-from urllib.parse import quote_from_bytes
+from time import monotonic
+from typing import TYPE_CHECKING, NamedTuple, TypeVar
+from urllib.parse import quote_from_bytes, unquote
 
 from dateparser import parse
-from httpx import AsyncClient, TimeoutException
-from pydantic import BaseModel, conint, validator
+from httpx import AsyncClient, TimeoutException, TransportError
+from pydantic import BaseModel, conint, root_validator, validator
 
 if TYPE_CHECKING:
+    from collections.abc import Awaitable, Callable, Iterator
     from typing import Any
 
     from httpx import Response
 
+T = TypeVar('T')
+
 BASE_URL = 'https://api.github.com'
+PAGE_SIZE = 100
+MAX_CONSECUTIVE_AUTH_FAILURES = 5
+ISO_8601_DURATION = re.compile(
+    r'P(?:(?P<years>\d+)Y)?(?:(?P<months>\d+)M)?(?:(?P<weeks>\d+)W)?(?:(?P<days>\d+)D)?'
+    r'(?:T(?:(?P<hours>\d+)H)?(?:(?P<minutes>\d+)M)?(?:(?P<seconds>\d+)S)?)?'
+)
+SHA256_DIGEST = re.compile(r'sha256:[0-9a-f]{64}')
+GITHUB_ACCOUNT_URL = re.compile(r'(?:https?://)?(?:www\.)?github\.com/(?:orgs/)?(?P<name>[^/?#]+)/?.*')
+COSIGN_TAG = re.compile(r'sha256-([0-9a-f]{64})\.(sig|att|sbom)')
+ENV_VAR_REFERENCE = re.compile(r'\$\{([A-Za-z_][A-Za-z0-9_]*)\}')
 
 
 class ImageName(NamedTuple):
@@ -42,18 +62,168 @@ class TimestampType(str, Enum):
     CREATED_AT = 'created_at'
 
 
+class MissingTimestampPolicy(str, Enum):
+    """
+    The on-missing-updated-at policy defines how to handle image versions without an updated_at timestamp.
+    """
+
+    FALLBACK = 'fallback'
+    SKIP = 'skip'
+    ERROR = 'error'
+
+
+class Decision(str, Enum):
+    """
+    What a selection rule thinks should happen to an image version.
+
+    When rules disagree, the strongest decision wins: PROTECT beats KEEP, which beats DELETE.
+    A version is only deleted if at least one rule selects it for deletion, and no rule keeps it.
+    """
+
+    PROTECT = 'protect'
+    KEEP = 'keep'
+    DELETE = 'delete'
+    NEUTRAL = 'neutral'
+
+
 class AccountType(str, Enum):
     """
     The user's account type defines which endpoints to use.
+
+    Auto is resolved to one of the others when the action starts.
     """
 
     ORG = 'org'
     PERSONAL = 'personal'
+    AUTO = 'auto'
+
+
+class PackageType(str, Enum):
+    """
+    The package-type defines which registry's packages to clean up.
+
+    Docker packages are the legacy docker.pkg.github.com packages, which some accounts still have.
+    """
+
+    CONTAINER = 'container'
+    DOCKER = 'docker'
+
+
+class VisibilityFilter(str, Enum):
+    """
+    The only-visibility setting defines which packages to clean up, by their visibility.
+
+    Private also covers internal packages, since neither can be pulled by the public.
+    """
+
+    ALL = 'all'
+    PUBLIC = 'public'
+    PRIVATE = 'private'
+
+
+class DeletionPriority(str, Enum):
+    """
+    The priority setting defines whether untagged or tagged versions of an image are deleted first.
+    """
+
+    UNTAGGED_FIRST = 'untagged-first'
+    TAGGED_FIRST = 'tagged-first'
+
+
+class SecondaryRateLimitPolicy(str, Enum):
+    """
+    The on-secondary-rate-limit policy defines what to do when GitHub's secondary rate limit kicks in.
+    """
+
+    RETRY = 'retry'
+    ABORT = 'abort'
+
+
+class EmptyAccountPolicy(str, Enum):
+    """
+    The on-empty-account policy defines what to do when the account has no packages at all.
+    """
+
+    OK = 'ok'
+    WARN = 'warn'
+    ERROR = 'error'
+
+
+class FailureCause(str, Enum):
+    """
+    Why we failed to delete an image version, which decides what can be done about it.
+    """
+
+    AUTH = 'auth'
+    RATE_LIMIT = 'rate-limit'
+    TRANSIENT = 'transient'
+    OTHER = 'other'
+
+
+class RequestPhase(str, Enum):
+    """
+    What a request to the Github API was sent for, for the rate limit summary.
+    """
+
+    LIST_PACKAGES = 'list packages'
+    LIST_VERSIONS = 'list versions'
+    DELETE = 'delete'
+    OTHER = 'other'
+
+
+class DeleteFailure(NamedTuple):
+    """
+    An image version we failed to delete, and the reason we got back.
+
+    The status is None when we never got a response, e.g., on timeouts.
+    """
+
+    package: str
+    version_id: int
+    status: int | None
+    message: str
+
+    @property
+    def image_name_with_tag(self) -> str:
+        return f'{self.package}:{self.version_id}'
+
+    @property
+    def cause(self) -> FailureCause:
+        if self.status is None or self.status >= 500:
+            return FailureCause.TRANSIENT
+        if self.status == 429 or (self.status == 403 and 'rate limit' in self.message.lower()):
+            return FailureCause.RATE_LIMIT
+        if self.status in (401, 403):
+            return FailureCause.AUTH
+        return FailureCause.OTHER
+
+    @property
+    def is_secondary_rate_limit(self) -> bool:
+        return self.status in (403, 429) and 'secondary rate limit' in self.message.lower()
 
 
 deleted: list[str] = []
-failed: list[str] = []
+# (image name, version ID, line) for each version a dry run would delete, printed once all images are handled
+dry_run_lines: list[tuple[str, int, str]] = []
+failed: list[DeleteFailure] = []
 needs_github_assistance: list[str] = []
+protected_digests: set[str] = set()
+protected_branch_tags: set[str] = set()
+requests_sent: Counter[RequestPhase] = Counter()
+time_spent: dict[RequestPhase, float] = dict.fromkeys(RequestPhase, 0.0)
+skipped_by_deadline: list[str] = []
+verified_deletions: list[str] = []
+unverified_deletions: list[str] = []
+deadline: float | None = None
+versions_scanned = 0
+abort_on_secondary_rate_limit = False
+abort_on_auth_errors = True
+consecutive_auth_failures = 0
+max_consecutive_failures = 0
+consecutive_failures = 0
+aborted = False
+abort_reason = ''
+versions_scanned_lock = asyncio.Lock()
 GITHUB_ASSISTANCE_MSG = (
     'Publicly visible package versions with more than '
     '5000 downloads cannot be deleted. '
@@ -61,40 +231,136 @@ GITHUB_ASSISTANCE_MSG = (
 )
 
 
+@contextmanager
+def timed(phase: RequestPhase) -> Iterator[None]:
+    """
+    Add the time spent in the block to the phase, for the timing summary.
+    """
+    start = monotonic()
+    try:
+        yield
+    finally:
+        time_spent[phase] += monotonic() - start
+
+
+def past_deadline() -> bool:
+    """
+    Whether the max-runtime has been reached, if one was set.
+    """
+    return deadline is not None and monotonic() >= deadline
+
+
 class PackageResponse(BaseModel):
     id: int
     name: str
     created_at: datetime
     updated_at: datetime
+    visibility: str = ''
+    version_count: int | None = None
 
 
-async def list_org_packages(*, org_name: str, http_client: AsyncClient) -> list[PackageResponse]:
+class PushedImage(BaseModel):
+    """
+    An image a build pushed, as listed in the pushed-manifest.
+    """
+
+    package: str
+    digest: str
+    tags: list[str] = []
+
+
+async def list_org_packages(
+    *, org_name: str, http_client: AsyncClient, package_type: PackageType = PackageType.CONTAINER
+) -> list[PackageResponse]:
     """
     List all packages, for an organization.
 
     :param org_name: The name of the organization.
     :param http_client: HTTP client.
+    :param package_type: The type of packages to list.
     :return: List of packages.
     """
-    response = await http_client.get(f'{BASE_URL}/orgs/{org_name}/packages?package_type=container')
-    response.raise_for_status()
-    return [PackageResponse(**i) for i in response.json()]
+    packages = await list_all_pages(
+        url=f'{BASE_URL}/orgs/{org_name}/packages?package_type={package_type.value}',
+        http_client=http_client,
+        phase=RequestPhase.LIST_PACKAGES,
+    )
+    return [PackageResponse(**i) for i in packages]
 
 
-async def list_packages(*, http_client: AsyncClient) -> list[PackageResponse]:
+async def list_packages(
+    *, http_client: AsyncClient, package_type: PackageType = PackageType.CONTAINER
+) -> list[PackageResponse]:
     """
     List all packages, for a user.
 
     :param http_client: HTTP client.
+    :param package_type: The type of packages to list.
     :return: List of packages.
     """
-    response = await http_client.get(f'{BASE_URL}/user/packages?package_type=container')
-    response.raise_for_status()
-    return [PackageResponse(**i) for i in response.json()]
+    packages = await list_all_pages(
+        url=f'{BASE_URL}/user/packages?package_type={package_type.value}',
+        http_client=http_client,
+        phase=RequestPhase.LIST_PACKAGES,
+    )
+    return [PackageResponse(**i) for i in packages]
+
+
+async def list_all_pages(
+    *,
+    url: str,
+    http_client: AsyncClient,
+    concurrency: int = 1,
+    max_pages: int | None = None,
+    phase: RequestPhase = RequestPhase.LIST_VERSIONS,
+    on_truncated: Callable[[], None] | None = None,
+) -> list[dict[str, Any]]:
+    """
+    Fetch every page of a paginated list endpoint.
+
+    Page numbers are computed up-front, rather than read from the `Link` header,
+    so that we can fetch `concurrency` pages at a time. We stop after the first
+    page that isn't full, or after `max_pages` pages.
+
+    :param url: The URL of the list endpoint, without pagination parameters.
+    :param http_client: HTTP client.
+    :param concurrency: How many pages to fetch at the same time.
+    :param max_pages: How many pages to fetch at most. Fetches all pages if None.
+    :param phase: What the requests are sent for, for the rate limit summary.
+    :param on_truncated: Called when we stop after `max_pages` pages, while there are more pages.
+    :return: The items of all pages, in order.
+    """
+    items: list[dict[str, Any]] = []
+    separator = '&' if '?' in url else '?'
+    page = 1
+    while max_pages is None or page <= max_pages:
+        page_count = concurrency if max_pages is None else min(concurrency, max_pages - page + 1)
+        requests_sent[phase] += page_count
+        responses = await asyncio.gather(
+            *[http_client.get(f'{url}{separator}per_page={PAGE_SIZE}&page={page + i}') for i in range(page_count)]
+        )
+        for response in responses:
+            response.raise_for_status()
+            page_items = response.json()
+            items.extend(page_items)
+            if len(page_items) < PAGE_SIZE:
+                return items
+        page += page_count
+    if on_truncated and 'next' in responses[-1].links:
+        # The last page was full, which only tells us there might be more. The Link header knows for sure
+        on_truncated()
+    return items
 
 
 async def list_org_package_versions(
-    *, org_name: str, image_name: ImageName, http_client: AsyncClient
+    *,
+    org_name: str,
+    image_name: ImageName,
+    http_client: AsyncClient,
+    concurrency: int = 1,
+    max_pages: int | None = None,
+    package_type: PackageType = PackageType.CONTAINER,
+    on_truncated: Callable[[], None] | None = None,
 ) -> list[dict[str, Any]]:
     """
     List image versions, for an organization.
@@ -102,39 +368,139 @@ async def list_org_package_versions(
     :param org_name: The name of the organization.
     :param image_name: The name of the container image.
     :param http_client: HTTP client.
+    :param concurrency: How many pages of versions to fetch at the same time.
+    :param max_pages: How many pages of versions to fetch at most. Fetches all pages if None.
+    :param package_type: The type of the package.
+    :param on_truncated: Called when max_pages stops us before the last page.
     :return: List of image objects.
     """
-    response = await http_client.get(
-        f'{BASE_URL}/orgs/{org_name}/packages/container/{image_name.encoded}/versions?per_page=100'
+    return await list_all_pages(
+        url=f'{BASE_URL}/orgs/{org_name}/packages/{package_type.value}/{image_name.encoded}/versions',
+        http_client=http_client,
+        concurrency=concurrency,
+        max_pages=max_pages,
+        on_truncated=on_truncated,
     )
-    response.raise_for_status()
-    return response.json()
 
 
-async def list_package_versions(*, image_name: ImageName, http_client: AsyncClient) -> list[dict]:
+async def list_package_versions(
+    *,
+    image_name: ImageName,
+    http_client: AsyncClient,
+    concurrency: int = 1,
+    max_pages: int | None = None,
+    package_type: PackageType = PackageType.CONTAINER,
+    on_truncated: Callable[[], None] | None = None,
+) -> list[dict[str, Any]]:
     """
     List image versions, for a personal account.
 
     :param image_name: The name of the container image.
     :param http_client: HTTP client.
+    :param concurrency: How many pages of versions to fetch at the same time.
+    :param max_pages: How many pages of versions to fetch at most. Fetches all pages if None.
+    :param package_type: The type of the package.
+    :param on_truncated: Called when max_pages stops us before the last page.
     :return: List of image objects.
     """
-    response = await http_client.get(f'{BASE_URL}/user/packages/container/{image_name.encoded}/versions?per_page=100')
+    return await list_all_pages(
+        url=f'{BASE_URL}/user/packages/{package_type.value}/{image_name.encoded}/versions',
+        http_client=http_client,
+        concurrency=concurrency,
+        max_pages=max_pages,
+        on_truncated=on_truncated,
+    )
+
+
+async def get_org_package_version(
+    *,
+    org_name: str,
+    image_name: ImageName,
+    version_id: int,
+    http_client: AsyncClient,
+    package_type: PackageType = PackageType.CONTAINER,
+) -> dict[str, Any] | None:
+    """
+    Get an image version, for an organization.
+
+    :param org_name: The name of the organization.
+    :param image_name: The name of the container image.
+    :param version_id: The ID of the image version.
+    :param http_client: HTTP client.
+    :param package_type: The type of the package.
+    :return: The image object, or None if it doesn't exist.
+    """
+    requests_sent[RequestPhase.OTHER] += 1
+    url = f'{BASE_URL}/orgs/{org_name}/packages/{package_type.value}/{image_name.encoded}/versions/{version_id}'
+    response = await http_client.get(url)
+    if response.status_code == 404:
+        return None
     response.raise_for_status()
     return response.json()
 
 
+async def get_package_version(
+    *,
+    image_name: ImageName,
+    version_id: int,
+    http_client: AsyncClient,
+    package_type: PackageType = PackageType.CONTAINER,
+) -> dict[str, Any] | None:
+    """
+    Get an image version, for a personal account.
+
+    :param image_name: The name of the container image.
+    :param version_id: The ID of the image version.
+    :param http_client: HTTP client.
+    :param package_type: The type of the package.
+    :return: The image object, or None if it doesn't exist.
+    """
+    requests_sent[RequestPhase.OTHER] += 1
+    url = f'{BASE_URL}/user/packages/{package_type.value}/{image_name.encoded}/versions/{version_id}'
+    response = await http_client.get(url)
+    if response.status_code == 404:
+        return None
+    response.raise_for_status()
+    return response.json()
+
+
+def track_failure_streaks(failure: DeleteFailure | None) -> None:
+    """
+    Abort the run when deletions keep failing, rather than failing every deletion that's left.
+
+    :param failure: How the last deletion failed, or None if it didn't.
+    """
+    global aborted, abort_reason, consecutive_auth_failures, consecutive_failures
+    consecutive_failures = consecutive_failures + 1 if failure else 0
+    consecutive_auth_failures = consecutive_auth_failures + 1 if failure and failure.cause == FailureCause.AUTH else 0
+    if abort_on_auth_errors and consecutive_auth_failures >= MAX_CONSECUTIVE_AUTH_FAILURES:
+        # A token that loses its permissions halfway through would fail every deletion that's left
+        aborted = True
+        abort_reason = f'{consecutive_auth_failures} deletions in a row failed with an auth error'
+    elif max_consecutive_failures and consecutive_failures >= max_consecutive_failures:
+        aborted = True
+        abort_reason = f'{consecutive_failures} deletions in a row failed'
+
+
 def post_deletion_output(*, response: Response, image_name: ImageName, version_id: int) -> None:
     """
     Output a little info to the user.
     """
+    global aborted, abort_reason
     image_name_with_tag = f'{image_name.value}:{version_id}'
+    failure = None
     if response.is_error:
         if response.status_code == 400 and response.json()['message'] == GITHUB_ASSISTANCE_MSG:
             # Output the names of these images in one block at the end
             needs_github_assistance.append(image_name_with_tag)
         else:
-            failed.append(image_name_with_tag)
+            body = response.json()
+            message = body.get('message', '') if isinstance(body, dict) else str(body)
+            failure = DeleteFailure(image_name.value, version_id, response.status_code, message)
+            failed.append(failure)
+            if abort_on_secondary_rate_limit and failure.is_secondary_rate_limit:
+                aborted = True
+                abort_reason = 'the Github API responded that the secondary rate limit was exceeded'
             print(
                 f'\nCouldn\'t delete {image_name_with_tag}.\n'
                 f'Status code: {response.status_code}\nResponse: {response.json()}\n'
@@ -142,10 +508,17 @@ def post_deletion_output(*, response: Response, image_name: ImageName, version_i
     else:
         deleted.append(image_name_with_tag)
         print(f'Deleted old image: {image_name_with_tag}')
+    track_failure_streaks(failure)
 
 
 async def delete_org_package_versions(
-    *, org_name: str, image_name: ImageName, version_id: int, http_client: AsyncClient, semaphore: Semaphore
+    *,
+    org_name: str,
+    image_name: ImageName,
+    version_id: int,
+    http_client: AsyncClient,
+    semaphore: Semaphore,
+    package_type: PackageType = PackageType.CONTAINER,
 ) -> None:
     """
     Delete an image version, for an organization.
@@ -154,21 +527,37 @@ async def delete_org_package_versions(
     :param image_name: The name of the container image.
     :param version_id: The ID of the image version we're deleting.
     :param http_client: HTTP client.
+    :param package_type: The type of the package.
     :return: Nothing - the API returns a 204.
     """
-    url = f'{BASE_URL}/orgs/{org_name}/packages/container/{image_name.encoded}/versions/{version_id}'
+    url = f'{BASE_URL}/orgs/{org_name}/packages/{package_type.value}/{image_name.encoded}/versions/{version_id}'
     await semaphore.acquire()
     try:
+        if aborted:
+            return
+        if past_deadline():
+            # Leave the rest for the next run, rather than getting killed halfway through
+            skipped_by_deadline.append(f'{image_name.value}:{version_id}')
+            return
+        requests_sent[RequestPhase.DELETE] += 1
         response = await http_client.delete(url)
         post_deletion_output(response=response, image_name=image_name, version_id=version_id)
     except TimeoutException as e:
+        failure = DeleteFailure(image_name.value, version_id, None, str(e))
+        failed.append(failure)
+        track_failure_streaks(failure)
         print(f'Request to delete {image_name.value} timed out with error `{e}`')
     finally:
         semaphore.release()
 
 
 async def delete_package_versions(
-    *, image_name: ImageName, version_id: int, http_client: AsyncClient, semaphore: Semaphore
+    *,
+    image_name: ImageName,
+    version_id: int,
+    http_client: AsyncClient,
+    semaphore: Semaphore,
+    package_type: PackageType = PackageType.CONTAINER,
 ) -> None:
     """
     Delete an image version, for a personal account.
@@ -176,19 +565,149 @@ async def delete_package_versions(
     :param image_name: The name of the container image.
     :param version_id: The ID of the image version we're deleting.
     :param http_client: HTTP client.
+    :param package_type: The type of the package.
     :return: Nothing - the API returns a 204.
     """
-    url = f'{BASE_URL}/user/packages/container/{image_name.encoded}/versions/{version_id}'
+    url = f'{BASE_URL}/user/packages/{package_type.value}/{image_name.encoded}/versions/{version_id}'
     await semaphore.acquire()
     try:
+        if aborted:
+            return
+        if past_deadline():
+            # Leave the rest for the next run, rather than getting killed halfway through
+            skipped_by_deadline.append(f'{image_name.value}:{version_id}')
+            return
+        requests_sent[RequestPhase.DELETE] += 1
         response = await http_client.delete(url)
         post_deletion_output(response=response, image_name=image_name, version_id=version_id)
     except TimeoutException as e:
+        failure = DeleteFailure(image_name.value, version_id, None, str(e))
+        failed.append(failure)
+        track_failure_streaks(failure)
         print(f'Request to delete {image_name.value} timed out with error `{e}`')
     finally:
         semaphore.release()
 
 
+async def get_rate_limit(*, http_client: AsyncClient) -> dict[str, int]:
+    """
+    Get the state of the current rate limit window.
+
+    Requests to the rate limit endpoint don't count against the rate limit.
+
+    :param http_client: HTTP client.
+    :return: The core rate limit, with the `limit`, `remaining` and `reset` (a unix timestamp) keys.
+    """
+    response = await http_client.get(f'{BASE_URL}/rate_limit')
+    response.raise_for_status()
+    return response.json()['resources']['core']
+
+
+def print_timing_summary(run_time: float) -> None:
+    """
+    Print how long each phase took.
+
+    Images are handled concurrently, so the time per phase is summed across images,
+    and can add up to more than the whole run took.
+    """
+    print('\nTime spent per phase, summed across images:')
+    for phase in (RequestPhase.LIST_PACKAGES, RequestPhase.LIST_VERSIONS, RequestPhase.DELETE):
+        print(f'\t- {phase.value}: {time_spent[phase]:.2f}s')
+    print(f'\t- total run: {run_time:.2f}s')
+
+
+def print_rate_limit_summary(starting_rate_limit: dict[str, int], rate_limit: dict[str, int]) -> None:
+    """
+    Print how many requests this run sent per phase, and the rate limit before and after the run.
+
+    The used requests are counted from the rate limit itself, so they include requests other
+    jobs sent with the same token while we ran.
+    """
+    print('\nRequests sent to the Github API:')
+    for phase in RequestPhase:
+        print(f'\t- {phase.value}: {requests_sent[phase]}')
+    print(f'\t- total: {sum(requests_sent.values())}')
+    print(f'{starting_rate_limit["remaining"]} of {starting_rate_limit["limit"]} requests left at the start of the run')
+    if starting_rate_limit['reset'] == rate_limit['reset']:
+        print(f'{starting_rate_limit["remaining"] - rate_limit["remaining"]} requests used from the rate limit')
+    else:
+        print('The rate limit was reset during the run')
+    reset = datetime.fromtimestamp(rate_limit['reset'], tz=timezone.utc)
+    print(f'{rate_limit["remaining"]} of {rate_limit["limit"]} requests left, until the rate limit resets at {reset}')
+
+
+def print_packages(all_packages: list[PackageResponse], image_names: set[ImageName]) -> None:
+    """
+    Print the packages we would delete from, for the packages-only action input.
+    """
+    packages = {package.name: package for package in all_packages}
+    print(f'Found {len(image_names)} package(s):')
+    for image_name in sorted(image_names):
+        package = packages[unquote(image_name.encoded)]
+        version_count = '' if package.version_count is None else f', {package.version_count} version(s)'
+        print(f'\t- {image_name.value}: created {package.created_at}, updated {package.updated_at}{version_count}')
+
+
+async def detect_account_type(*, owner: str, http_client: AsyncClient) -> AccountType:
+    """
+    Find out whether an account is an organization or a personal account.
+
+    :param owner: The name of the account.
+    :param http_client: HTTP client.
+    :return: The account type.
+    """
+    requests_sent[RequestPhase.OTHER] += 1
+    response = await http_client.get(f'{BASE_URL}/orgs/{owner}')
+    if response.status_code == 404:
+        return AccountType.PERSONAL
+    response.raise_for_status()
+    return AccountType.ORG
+
+
+async def get_authenticated_user(*, http_client: AsyncClient) -> str:
+    """
+    Get the login of the account the token belongs to.
+
+    :param http_client: HTTP client.
+    :return: The login of the authenticated user.
+    """
+    requests_sent[RequestPhase.OTHER] += 1
+    response = await http_client.get(f'{BASE_URL}/user')
+    response.raise_for_status()
+    return response.json()['login']
+
+
+async def list_release_digests(*, repository: str, count: int, http_client: AsyncClient) -> set[str]:
+    """
+    List the image digests referenced in the release notes of a repository's latest releases.
+
+    :param repository: The repository, formatted as owner/name.
+    :param count: How many of the latest releases to look at.
+    :param http_client: HTTP client.
+    :return: Set of digests, like sha256:<hex>.
+    """
+    requests_sent[RequestPhase.OTHER] += 1
+    response = await http_client.get(f'{BASE_URL}/repos/{repository}/releases?per_page={count}')
+    response.raise_for_status()
+    return {digest for release in response.json() for digest in SHA256_DIGEST.findall(release.get('body') or '')}
+
+
+async def list_branch_tags(*, repository: str, http_client: AsyncClient) -> set[str]:
+    """
+    List the image tags of a repository's branches, formatted as branch-<name>.
+
+    Slashes aren't allowed in image tags, so a branch like feature/x is tagged branch-feature-x.
+
+    :param repository: The repository, formatted as owner/name.
+    :param http_client: HTTP client.
+    :return: Set of image tags.
+    """
+    branches = await list_all_pages(
+        url=f'{BASE_URL}/repos/{repository}/branches', http_client=http_client, phase=RequestPhase.OTHER
+    )
+    return {f'branch-{branch["name"].replace("/", "-")}' for branch in branches}
+
+
 class GithubAPI:
     """
     Provide a unified API, regardless of account type.
@@ -196,21 +715,71 @@ class GithubAPI:
 
     @staticmethod
     async def list_packages(
-        *, account_type: AccountType, org_name: str | None, http_client: AsyncClient
+        *,
+        account_type: AccountType,
+        org_name: str | None,
+        http_client: AsyncClient,
+        package_type: PackageType = PackageType.CONTAINER,
     ) -> list[PackageResponse]:
         if account_type != AccountType.ORG:
-            return await list_packages(http_client=http_client)
+            return await list_packages(http_client=http_client, package_type=package_type)
         assert isinstance(org_name, str)
-        return await list_org_packages(org_name=org_name, http_client=http_client)
+        return await list_org_packages(org_name=org_name, http_client=http_client, package_type=package_type)
 
     @staticmethod
     async def list_package_versions(
-        *, account_type: AccountType, org_name: str | None, image_name: ImageName, http_client: AsyncClient
+        *,
+        account_type: AccountType,
+        org_name: str | None,
+        image_name: ImageName,
+        http_client: AsyncClient,
+        concurrency: int = 1,
+        max_pages: int | None = None,
+        package_type: PackageType = PackageType.CONTAINER,
+        on_truncated: Callable[[], None] | None = None,
     ) -> list[dict[str, Any]]:
         if account_type != AccountType.ORG:
-            return await list_package_versions(image_name=image_name, http_client=http_client)
+            return await list_package_versions(
+                image_name=image_name,
+                http_client=http_client,
+                concurrency=concurrency,
+                max_pages=max_pages,
+                package_type=package_type,
+                on_truncated=on_truncated,
+            )
         assert isinstance(org_name, str)
-        return await list_org_package_versions(org_name=org_name, image_name=image_name, http_client=http_client)
+        return await list_org_package_versions(
+            org_name=org_name,
+            image_name=image_name,
+            http_client=http_client,
+            concurrency=concurrency,
+            max_pages=max_pages,
+            package_type=package_type,
+            on_truncated=on_truncated,
+        )
+
+    @staticmethod
+    async def get_package_version(
+        *,
+        account_type: AccountType,
+        org_name: str | None,
+        image_name: ImageName,
+        version_id: int,
+        http_client: AsyncClient,
+        package_type: PackageType = PackageType.CONTAINER,
+    ) -> dict[str, Any] | None:
+        if account_type != AccountType.ORG:
+            return await get_package_version(
+                image_name=image_name, version_id=version_id, http_client=http_client, package_type=package_type
+            )
+        assert isinstance(org_name, str)
+        return await get_org_package_version(
+            org_name=org_name,
+            image_name=image_name,
+            version_id=version_id,
+            http_client=http_client,
+            package_type=package_type,
+        )
 
     @staticmethod
     async def delete_package(
@@ -221,10 +790,15 @@ class GithubAPI:
         version_id: int,
         http_client: AsyncClient,
         semaphore: Semaphore,
+        package_type: PackageType = PackageType.CONTAINER,
     ) -> None:
         if account_type != AccountType.ORG:
             return await delete_package_versions(
-                image_name=image_name, version_id=version_id, http_client=http_client, semaphore=semaphore
+                image_name=image_name,
+                version_id=version_id,
+                http_client=http_client,
+                semaphore=semaphore,
+                package_type=package_type,
             )
         assert isinstance(org_name, str)
         return await delete_org_package_versions(
@@ -233,9 +807,118 @@ class GithubAPI:
             version_id=version_id,
             http_client=http_client,
             semaphore=semaphore,
+            package_type=package_type,
         )
 
 
+def parse_iso_8601_duration(v: str) -> timedelta | None:
+    """
+    Parse an ISO 8601 duration like 'P90D', 'P1Y2M' or 'PT48H'.
+
+    Years and months don't have a fixed length, so we treat them as 365 and 30 days.
+
+    :return: The duration, or None if the value isn't an ISO 8601 duration.
+    """
+    value = v.strip().upper()
+    match = ISO_8601_DURATION.fullmatch(value)
+    if not match or not any(match.groupdict().values()) or value.endswith('T'):
+        return None
+    parts = {k: int(i) if i else 0 for k, i in match.groupdict().items()}
+    return timedelta(
+        days=parts['years'] * 365 + parts['months'] * 30 + parts['weeks'] * 7 + parts['days'],
+        hours=parts['hours'],
+        minutes=parts['minutes'],
+        seconds=parts['seconds'],
+    )
+
+
+def parse_cut_off(v: str) -> datetime:
+    """
+    Parse a cut-off, given as a human readable datetime or as an ISO 8601 duration.
+    """
+    if (duration := parse_iso_8601_duration(v)) is not None:
+        return datetime.now(timezone.utc) - duration
+    parsed_cutoff = parse(v)
+    if not parsed_cutoff:
+        raise ValueError(f"Unable to parse '{v}'")
+    elif parsed_cutoff.tzinfo is None or parsed_cutoff.tzinfo.utcoffset(parsed_cutoff) is None:
+        raise ValueError('Timezone is required for the cut-off')
+    return parsed_cutoff
+
+
+def interpolate_env_vars(v: str) -> str:
+    """
+    Replace ${VAR} references with the value of the environment variable.
+
+    Unset variables are an error, since silently dropping them could turn
+    a filter like 'pr-${PR_NUMBER}' into 'pr-', and match the wrong images.
+    """
+
+    def replace(match: re.Match[str]) -> str:
+        if (value := environ.get(match.group(1))) is None:
+            raise ValueError(f'Environment variable {match.group(1)} is not set')
+        return value
+
+    return ENV_VAR_REFERENCE.sub(replace, v)
+
+
+def split_outside_braces(v: str) -> list[str]:
+    """
+    Split a string on commas, except for commas inside braces, or escaped with a backslash.
+    """
+    parts: list[str] = []
+    depth, start, i = 0, 0, 0
+    while i < len(v):
+        if v[i] == '\\':
+            i += 1
+        elif v[i] == '{':
+            depth += 1
+        elif v[i] == '}' and depth:
+            depth -= 1
+        elif v[i] == ',' and not depth:
+            parts.append(v[start:i])
+            start = i + 1
+        i += 1
+    return parts + [v[start:]]
+
+
+def expand_braces(v: str) -> list[str]:
+    """
+    Expand brace groups the way shells do, so 'service-{api,worker}' becomes ['service-api', 'service-worker'].
+
+    Groups can be nested or repeated. Groups without a comma, and braces escaped
+    with a backslash, are kept as they are.
+    """
+    depth, start, i = 0, 0, 0
+    while i < len(v):
+        if v[i] == '\\':
+            i += 1
+        elif v[i] == '{':
+            if not depth:
+                start = i
+            depth += 1
+        elif v[i] == '}' and depth:
+            depth -= 1
+            if not depth and len(alternatives := split_outside_braces(v[start + 1 : i])) > 1:
+                prefix, suffix = v[:start], v[i + 1 :]
+                return [name for alternative in alternatives for name in expand_braces(prefix + alternative + suffix)]
+        i += 1
+    return [re.sub(r'\\([{},\\])', r'\1', v)]
+
+
+def normalize_org_name(v: str) -> str:
+    """
+    Turn the forms org names are commonly pasted in, like '@acme' or 'https://github.com/orgs/acme', into 'acme'.
+    """
+    name = v.strip()
+    if match := GITHUB_ACCOUNT_URL.fullmatch(name):
+        name = match.group('name')
+    name = name.removeprefix('@')
+    if name != v:
+        print(f"Using '{name}' as the org-name, rather than '{v}'")
+    return name
+
+
 class Inputs(BaseModel):
     image_names: list[str]
     cut_off: datetime
@@ -247,116 +930,765 @@ class Inputs(BaseModel):
     keep_at_least: conint(ge=0) = 0  # type: ignore[valid-type]
     filter_tags: list[str]
     filter_include_untagged: bool = True
-
-    @validator('skip_tags', 'filter_tags', 'image_names', pre=True)
+    on_missing_updated_at: MissingTimestampPolicy = MissingTimestampPolicy.FALLBACK
+    dry_run: bool = False
+    concurrency_per_package: conint(ge=1) = 1  # type: ignore[valid-type]
+    report_only_changes: bool = False
+    all_packages: bool = False
+    keep_at_least_one: bool = False
+    untagged_cut_off: datetime | None = None
+    dry_run_nonempty_exit_code: conint(ge=0, le=255) = 0  # type: ignore[valid-type]
+    max_pages: conint(ge=0) = 0  # type: ignore[valid-type]
+    strip_prefix: str = ''
+    delete_duplicate_tags: bool = False
+    protect_last_releases: conint(ge=0, le=100) = 0  # type: ignore[valid-type]
+    min_rate_limit: conint(ge=0) = 0  # type: ignore[valid-type]
+    explain_rate_limit: bool = False
+    package_type: PackageType = PackageType.CONTAINER
+    max_runtime: timedelta | None = None
+    protect_active_branches: bool = False
+    print_config: bool = False
+    version_ids: list[int] = []
+    parallel_deletes: conint(ge=1) = 50  # type: ignore[valid-type]
+    max_versions_scanned: conint(ge=0) = 0  # type: ignore[valid-type]
+    exclude_created_today: bool = False
+    only_visibility: VisibilityFilter = VisibilityFilter.ALL
+    verify_plan: bool = False
+    on_secondary_rate_limit: SecondaryRateLimitPolicy = SecondaryRateLimitPolicy.RETRY
+    package_name_regex: re.Pattern | None = None
+    package_name_regex_exclude: re.Pattern | None = None
+    timing: bool = False
+    tag_cut_offs: list[tuple[str, datetime]] = []
+    confirm: str = ''
+    packages_only: bool = False
+    retry_failed_from: list[tuple[str, int]] = []
+    filter_version_names: list[str] = []
+    on_empty_account: EmptyAccountPolicy = EmptyAccountPolicy.WARN
+    verify_deletions: bool = False
+    warn_tags_over: conint(ge=0) = 0  # type: ignore[valid-type]
+    cleanup_cosign_orphans: bool = False
+    skip_if_updated_within: timedelta | None = None
+    match_short_digest: bool = False
+    halt_on_auth_errors: bool = True
+    priority: DeletionPriority = DeletionPriority.UNTAGGED_FIRST
+    pushed_manifest: list[PushedImage] = []
+    untagged_older_than_tagged: bool = False
+    circuit_breaker_threshold: conint(ge=0) = 0  # type: ignore[valid-type]
+
+    @validator('filter_tags', pre=True)
+    def interpolate_filter_tags(cls, v: str) -> str:
+        return interpolate_env_vars(v) if v else v
+
+    @validator('skip_tags', 'filter_tags', 'filter_version_names', pre=True)
     def parse_comma_separate_string_as_list(cls, v: str) -> list[str]:
         return [i.strip() for i in v.split(',')] if v else []
 
+    @validator('image_names', pre=True)
+    def parse_image_names(cls, v: str) -> list[str]:
+        return [name.strip() for i in split_outside_braces(v) for name in expand_braces(i.strip())] if v else []
+
     @validator('cut_off', pre=True)
     def parse_human_readable_datetime(cls, v: str) -> datetime:
-        parsed_cutoff = parse(v)
-        if not parsed_cutoff:
-            raise ValueError(f"Unable to parse '{v}'")
-        elif parsed_cutoff.tzinfo is None or parsed_cutoff.tzinfo.utcoffset(parsed_cutoff) is None:
-            raise ValueError('Timezone is required for the cut-off')
-        return parsed_cutoff
+        return parse_cut_off(v)
+
+    @validator('untagged_cut_off', pre=True, always=True)
+    def parse_untagged_cut_off(cls, v: str | None, values: dict) -> datetime | None:
+        if not v:
+            # Untagged images use the same cut-off as tagged images by default
+            return values.get('cut_off')
+        return parse_cut_off(v)
+
+    @validator('max_runtime', pre=True)
+    def parse_max_runtime(cls, v: str | None) -> timedelta | None:
+        if not v:
+            return None
+        if (duration := parse_iso_8601_duration(v)) is None:
+            raise ValueError(f"Unable to parse '{v}'. The max-runtime must be an ISO 8601 duration, like PT30M")
+        return duration
+
+    @validator('skip_if_updated_within', pre=True)
+    def parse_skip_if_updated_within(cls, v: str | None) -> timedelta | None:
+        if not v:
+            return None
+        if (duration := parse_iso_8601_duration(v)) is None:
+            raise ValueError(
+                f"Unable to parse '{v}'. The skip-if-updated-within must be an ISO 8601 duration, like PT15M"
+            )
+        return duration
+
+    @validator('pushed_manifest', pre=True)
+    def read_pushed_manifest(cls, v: str) -> list[dict]:
+        if not v:
+            return []
+        pushed = json.loads(Path(v).read_text())
+        if not isinstance(pushed, list):
+            raise ValueError('The pushed-manifest must hold a JSON list of {"package", "digest", "tags"} objects')
+        return pushed
+
+    @validator('version_ids', pre=True)
+    def parse_version_ids(cls, v: str, values: dict) -> list[int]:
+        version_ids = [i.strip() for i in v.split(',')] if v else []
+        if not all(i.isdigit() for i in version_ids):
+            raise ValueError('version-ids must be a comma-separated list of numeric version IDs')
+        image_names = values.get('image_names') or []
+        if version_ids and (len(image_names) != 1 or any(c in image_names[0] for c in '*?[')):
+            raise ValueError('version-ids requires image-names to be a single image name, without wildcards')
+        return [int(i) for i in version_ids]
+
+    @validator('package_name_regex', 'package_name_regex_exclude', pre=True)
+    def compile_package_name_regex(cls, v: str | None) -> re.Pattern[str] | None:
+        if not v:
+            return None
+        try:
+            return re.compile(v)
+        except re.error as e:
+            raise ValueError(f"Unable to compile '{v}': {e}") from e
+
+    @validator('tag_cut_offs', pre=True)
+    def parse_tag_cut_offs(cls, v: str) -> list[tuple[str, datetime]]:
+        tag_cut_offs = []
+        # Dates like 'May 26, 2021' contain commas, so pairs are separated by semicolons or newlines
+        for item in [i.strip() for i in re.split(r'[;\n]', v) if i.strip()] if v else []:
+            if ':' not in item:
+                raise ValueError(f"Unable to parse '{item}'. Tag cut-offs must be formatted as <pattern>:<cut-off>")
+            pattern, cut_off = item.split(':', 1)
+            tag_cut_offs.append((pattern.strip(), parse_cut_off(cut_off.strip())))
+        return tag_cut_offs
+
+    @validator('retry_failed_from', pre=True)
+    def read_retry_failed_from(cls, v: str) -> list[tuple[str, int]]:
+        if not v:
+            return []
+        retries = []
+        # The file holds the failed output of a previous run, so entries are separated by commas or newlines
+        for entry in [i.strip() for i in re.split(r'[,\n]', Path(v).read_text()) if i.strip()]:
+            package, _, version_id = entry.rpartition(':')
+            if not package or not version_id.isdigit():
+                raise ValueError(f"Unable to parse '{entry}'. Failures to retry must be formatted as <package>:<id>")
+            retries.append((package, int(version_id)))
+        return retries
+
+    @validator('all_packages', always=True)
+    def validate_all_packages(cls, v: bool, values: dict) -> bool:
+        if v and values.get('image_names'):
+            raise ValueError('image-names cannot be combined with all-packages')
+        if not v and values.get('image_names') == []:
+            raise ValueError('image-names is required, unless all-packages is enabled')
+        return v
 
     @validator('org_name', pre=True)
     def validate_org_name(cls, v: str, values: dict) -> str | None:
         if values['account_type'] == AccountType.ORG and not v:
             raise ValueError('org-name is required when account-type is org')
         if v:
-            return v
+            return normalize_org_name(v)
+        return None
+
+    @root_validator(skip_on_failure=True)
+    def move_tags_out_of_image_names(cls, values: dict) -> dict:
+        # Package names never contain tags, so 'webapp:latest' would never match anything
+        tags = {image_name.split(':', 1)[1] if ':' in image_name else None for image_name in values['image_names']}
+        if len(tags) > 1:
+            # The filter-tags apply to every image, so they only stand in for a tag every image name has
+            raise ValueError(
+                'Image names with tags can only be combined with image names with the same tag, since the tag is '
+                'moved to the filter-tags, which apply to every image. Use separate steps for the other images'
+            )
+        for i, image_name in enumerate(values['image_names']):
+            if ':' in image_name:
+                name, tag = image_name.split(':', 1)
+                print(
+                    f"Image names can't contain tags. Treating '{image_name}' as image name '{name}', "
+                    f"and adding '{tag}' to the filter-tags"
+                )
+                values['image_names'][i] = name
+                if tag not in values['filter_tags']:
+                    values['filter_tags'].append(tag)
+        return values
+
+
+def print_resolved_config(inputs: Inputs) -> None:
+    """
+    Print the inputs the way they were parsed, with defaults filled in.
+
+    The token isn't part of the inputs, and is only ever printed redacted.
+    """
+    print('Resolved configuration:')
+    for name, value in inputs.dict().items():
+        if isinstance(value, list):
+            value = ', '.join(':'.join(map(str, i)) if isinstance(i, tuple) else str(i) for i in value)
+        elif isinstance(value, Enum):
+            value = value.value
+        print(f'\t- {name.replace("_", "-")}: {value}')
+    print('\t- token: ***')
+
+
+def get_relevant_timestamp(version: dict[str, Any], inputs: Inputs) -> str | None:
+    """
+    Return the timestamp to compare against the cut-off, for an image version.
+
+    If the user wants to use updated_at timestamps and a version has none, the
+    on-missing-updated-at policy decides whether we fall back to created_at,
+    skip the version (by returning None), or abort.
+    """
+    if inputs.timestamp_to_use != TimestampType.UPDATED_AT or version.get('updated_at'):
+        return version.get(inputs.timestamp_to_use.value) or ''
+
+    if inputs.on_missing_updated_at == MissingTimestampPolicy.ERROR:
+        raise ValueError(f'Image version {version["id"]} has no updated_at timestamp')
+    elif inputs.on_missing_updated_at == MissingTimestampPolicy.SKIP:
+        print(f'Skipping image version {version["id"]}. No updated_at timestamp.')
         return None
 
+    print(f'Image version {version["id"]} has no updated_at timestamp. Using created_at instead.')
+    return version.get('created_at') or ''
+
+
+def get_image_tags(version: dict[str, Any]) -> list[str]:
+    """
+    Return the tags of an image version, or an empty list for untagged versions.
+
+    Legacy docker packages list their tags under metadata.docker.tag, rather than metadata.container.tags.
+    """
+    if 'metadata' in version and 'container' in version['metadata'] and 'tags' in version['metadata']['container']:
+        return version['metadata']['container']['tags']
+    if 'metadata' in version and 'docker' in version['metadata'] and 'tag' in version['metadata']['docker']:
+        return version['metadata']['docker']['tag']
+    return []
+
+
+def get_matchable_tags(version: dict[str, Any], inputs: Inputs) -> list[str]:
+    """
+    Return the tags to match filter-tags and skip-tags against.
+
+    With match-short-digest, the first 12 characters of the digest count as a tag too, like they do in `docker images`.
+    """
+    image_tags = get_image_tags(version)
+    name = version.get('name') or ''
+    if inputs.match_short_digest and name.startswith('sha256:'):
+        return [*image_tags, name.removeprefix('sha256:')[:12]]
+    return image_tags
+
+
+def cut_off_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Select versions older than the cut-off for deletion.
+
+    Untagged versions are compared to the untagged-cut-off instead. Tags matching one of the
+    tag-cut-offs use the cut-off of the first pattern they match, and a tagged version is only
+    selected once every one of its tags is past its cut-off.
+    """
+    image_tags = get_image_tags(version)
+    if not image_tags:
+        assert inputs.untagged_cut_off is not None
+        return Decision.DELETE if timestamp <= inputs.untagged_cut_off else Decision.NEUTRAL
+
+    cut_offs = [
+        next((cut_off for pattern, cut_off in inputs.tag_cut_offs if fnmatch(tag, pattern)), inputs.cut_off)
+        for tag in image_tags
+    ]
+    return Decision.DELETE if timestamp <= min(cut_offs) else Decision.NEUTRAL
+
+
+def untagged_only_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Keep tagged versions, if only untagged versions should be deleted.
+    """
+    return Decision.KEEP if inputs.untagged_only and get_image_tags(version) else Decision.NEUTRAL
+
+
+def filter_include_untagged_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Keep untagged versions, if the filter-include-untagged setting is False.
+    """
+    return Decision.KEEP if not inputs.filter_include_untagged and not get_image_tags(version) else Decision.NEUTRAL
+
+
+def filter_tags_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Keep versions without any tags matching the filter-tags, if specified.
+
+    One thing to note here is that we use fnmatch to support wildcards.
+    A filter-tags setting of 'some-tag-*' should match to both 'some-tag-1' and 'some-tag-2'.
+    """
+    if not inputs.filter_tags:
+        return Decision.NEUTRAL
+    image_tags = get_matchable_tags(version, inputs)
+    if any(fnmatch(tag, filter_tag) for filter_tag in inputs.filter_tags for tag in image_tags):
+        return Decision.NEUTRAL
+    return Decision.KEEP
+
+
+def filter_version_names_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Keep versions whose name doesn't match any of the filter-version-names, if specified.
+
+    For containers, the version name is the image digest, so 'sha256:ab*' matches every digest starting with 'ab'.
+    """
+    if not inputs.filter_version_names:
+        return Decision.NEUTRAL
+    if any(fnmatch(version.get('name') or '', pattern) for pattern in inputs.filter_version_names):
+        return Decision.NEUTRAL
+    return Decision.KEEP
+
+
+def skip_tags_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Protect versions tagged with any of the skip-tags.
+    """
+    image_tags = get_matchable_tags(version, inputs)
+    if any(fnmatch(tag, skip_tag) for skip_tag in inputs.skip_tags for tag in image_tags):
+        return Decision.PROTECT
+    return Decision.NEUTRAL
+
+
+def release_digests_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Protect versions whose digest is referenced by one of the latest releases, or listed in the pushed-manifest.
+    """
+    return Decision.PROTECT if version.get('name') in protected_digests else Decision.NEUTRAL
+
+
+def active_branches_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Protect versions tagged for a branch that still exists, if protect-active-branches is enabled.
+    """
+    return Decision.PROTECT if protected_branch_tags.intersection(get_image_tags(version)) else Decision.NEUTRAL
+
+
+def created_today_rule(version: dict[str, Any], timestamp: datetime, inputs: Inputs) -> Decision:
+    """
+    Protect versions created on the current UTC day, if exclude-created-today is enabled.
+
+    This looks at created_at, regardless of the timestamp-to-use, since it guards against
+    deleting images that were just built.
+    """
+    if not inputs.exclude_created_today or not (created_at := parse(version.get('created_at') or '')):
+        return Decision.NEUTRAL
+    if created_at.tzinfo is None:
+        created_at = created_at.replace(tzinfo=timezone.utc)
+    today = datetime.now(timezone.utc).date()
+    return Decision.PROTECT if created_at.astimezone(timezone.utc).date() == today else Decision.NEUTRAL
+
+
+# The rules every image version is run through. Each rule only looks at a single version,
+# so anything that depends on the other versions of an image (like keep-at-least) happens outside of them.
+SELECTION_RULES: list[Callable[[dict[str, Any], datetime, Inputs], Decision]] = [
+    cut_off_rule,
+    untagged_only_rule,
+    filter_include_untagged_rule,
+    filter_tags_rule,
+    filter_version_names_rule,
+    skip_tags_rule,
+    release_digests_rule,
+    active_branches_rule,
+    created_today_rule,
+]
+
+
+def resolve_decisions(decisions: list[Decision]) -> bool:
+    """
+    Resolve the decisions of all selection rules for a version.
+
+    :return: Whether to delete the version.
+    """
+    for decision in (Decision.PROTECT, Decision.KEEP, Decision.DELETE):
+        if decision in decisions:
+            return decision == Decision.DELETE
+    return False
+
+
+def find_stale_duplicates(versions: list[dict[str, Any]]) -> set[int]:
+    """
+    Find image versions whose tags are all also held by a newer version.
+
+    GitHub can briefly leave the same tag on several versions while retagging. Versions are
+    listed newest first, so the first version holding a tag is its current holder. Versions
+    with at least one tag nobody newer has, are never considered stale.
+
+    :return: The IDs of the stale versions.
+    """
+    seen_tags: set[str] = set()
+    stale_version_ids = set()
+    for version in versions:
+        image_tags = get_image_tags(version)
+        if image_tags and all(tag in seen_tags for tag in image_tags):
+            stale_version_ids.add(version['id'])
+        seen_tags.update(image_tags)
+    return stale_version_ids
+
+
+def find_oldest_tagged(versions: list[dict[str, Any]], inputs: Inputs) -> datetime | None:
+    """
+    Find the timestamp of the oldest tagged version, for the untagged-older-than-tagged action input.
+
+    Unlike get_relevant_timestamp, this quietly falls back to created_at for versions without an
+    updated_at. They're still tagged versions of the image, and the on-missing-updated-at policy is
+    applied, and logged, when each version is selected.
+
+    :return: The timestamp, or None if no version is tagged.
+    """
+    timestamps = [
+        parse(timestamp)
+        for version in versions
+        if get_image_tags(version)
+        and (timestamp := version.get(inputs.timestamp_to_use.value) or version.get('created_at'))
+    ]
+    return min((timestamp for timestamp in timestamps if timestamp), default=None)
+
+
+def find_cosign_orphans(versions: list[dict[str, Any]], deleted_digests: set[str]) -> set[int]:
+    """
+    Find cosign signature, attestation and SBOM versions whose image is gone.
+
+    Cosign tags its artifacts sha256-<digest>.sig (or .att, .sbom), after the digest of the image they belong to.
+    Only versions with no other tags are considered, so an image that was also tagged by hand is never an orphan.
+
+    :param deleted_digests: The digests of the versions we're about to delete.
+    :return: The IDs of the orphaned versions.
+    """
+    remaining_digests = {version.get('name') for version in versions} - deleted_digests
+    orphan_version_ids = set()
+    for version in versions:
+        matches = [COSIGN_TAG.fullmatch(tag) for tag in get_image_tags(version)]
+        if matches and all(m and f'sha256:{m.group(1)}' not in remaining_digests for m in matches):
+            orphan_version_ids.add(version['id'])
+    return orphan_version_ids
+
+
+async def get_package_versions(
+    *, image_name: ImageName, version_ids: list[int], inputs: Inputs, http_client: AsyncClient
+) -> list[dict[str, Any] | None]:
+    """
+    Get image versions one by one, sending at most concurrency-per-package requests at a time, like when listing them.
 
-async def get_and_delete_old_versions(image_name: ImageName, inputs: Inputs, http_client: AsyncClient) -> None:
+    :return: The image versions, in the order of the IDs, with None for versions that don't exist.
+    """
+    semaphore = Semaphore(inputs.concurrency_per_package)
+
+    async def get_package_version(version_id: int) -> dict[str, Any] | None:
+        async with semaphore:
+            return await GithubAPI.get_package_version(
+                account_type=inputs.account_type,
+                org_name=inputs.org_name,
+                image_name=image_name,
+                version_id=version_id,
+                http_client=http_client,
+                package_type=inputs.package_type,
+            )
+
+    return await asyncio.gather(*[get_package_version(version_id) for version_id in version_ids])
+
+
+async def verify_plan(
+    *, image_name: ImageName, versions: list[dict[str, Any]], inputs: Inputs, http_client: AsyncClient
+) -> list[dict[str, Any]]:
+    """
+    Check that the versions we plan to delete haven't changed since we listed them.
+
+    Versions that were deleted or retagged in the meantime are reported as drift, and dropped
+    from the plan, since our decision to delete them was based on outdated information.
+
+    :return: The versions that are still as we listed them.
+    """
+    current_versions = await get_package_versions(
+        image_name=image_name,
+        version_ids=[version['id'] for version in versions],
+        inputs=inputs,
+        http_client=http_client,
+    )
+    verified_versions = []
+    for version, current_version in zip(versions, current_versions):
+        image_name_with_tag = f'{image_name.value}:{version["id"]}'
+        if current_version is None:
+            print(f'Plan drift for {image_name_with_tag}: the version no longer exists')
+        elif sorted(get_image_tags(current_version)) != sorted(get_image_tags(version)):
+            print(
+                f'Plan drift for {image_name_with_tag}: tags changed from '
+                f'[{", ".join(get_image_tags(version))}] to [{", ".join(get_image_tags(current_version))}]'
+            )
+        else:
+            verified_versions.append(version)
+    return verified_versions
+
+
+async def verify_deletions(
+    *, image_name: ImageName, version_ids: list[int], inputs: Inputs, http_client: AsyncClient
+) -> None:
+    """
+    Check that the versions Github reported as deleted are really gone.
+
+    This costs a request per deletion, sent at most concurrency-per-package at a time, and
+    verification is skipped once the run is aborted or out of time.
+    """
+    if aborted or past_deadline():
+        return
+    current_versions = await get_package_versions(
+        image_name=image_name, version_ids=version_ids, inputs=inputs, http_client=http_client
+    )
+    for version_id, current_version in zip(version_ids, current_versions):
+        image_name_with_tag = f'{image_name.value}:{version_id}'
+        if current_version is None:
+            verified_deletions.append(image_name_with_tag)
+        else:
+            unverified_deletions.append(image_name_with_tag)
+            print(f'Image version {image_name_with_tag} still exists, after Github reported it as deleted')
+
+
+def dry_run_output(*, image_name: ImageName, version: dict[str, Any]) -> None:
+    """
+    Record what we would have deleted, and which tags point to it.
+
+    Images are handled concurrently, so the line is printed by print_dry_run_output once all images are done.
+    """
+    image_tags = get_image_tags(version)
+    references = f'tags: {", ".join(image_tags)}' if image_tags else 'untagged'
+    line = f'Would delete image {image_name.value}:{version["id"]} ({version.get("name")}, {references})'
+    dry_run_lines.append((image_name.value, version['id'], line))
+
+
+def print_dry_run_output() -> None:
+    """
+    Print what a dry run would have deleted, sorted by image name and version ID.
+
+    The versions are added to the deleted output in the same order, so later steps can plan on what a real run
+    would delete, and the plan of one dry run can be diffed against the next. Other messages are printed as images
+    are handled, so only these lines have a stable order.
+    """
+    for image_name, version_id, line in sorted(dry_run_lines):
+        print(line)
+        deleted.append(f'{image_name}:{version_id}')
+    dry_run_lines.clear()
+
+
+async def delete_version_ids(
+    image_name: ImageName,
+    version_ids: list[int],
+    inputs: Inputs,
+    http_client: AsyncClient,
+    semaphore: Semaphore | None = None,
+) -> bool:
+    """
+    Delete the given image versions, without listing or selecting versions.
+
+    Used for the version-ids and retry-failed-from action inputs.
+
+    :return: Whether any versions were selected for deletion.
+    """
+    if inputs.dry_run:
+        for version_id in version_ids:
+            dry_run_lines.append((image_name.value, version_id, f'Would delete image {image_name.value}:{version_id}'))
+        return bool(version_ids)
+
+    sem = semaphore or Semaphore(inputs.parallel_deletes)
+    with timed(RequestPhase.DELETE):
+        await asyncio.gather(
+            *[
+                GithubAPI.delete_package(
+                    account_type=inputs.account_type,
+                    org_name=inputs.org_name,
+                    image_name=image_name,
+                    version_id=version_id,
+                    http_client=http_client,
+                    semaphore=sem,
+                    package_type=inputs.package_type,
+                )
+                for version_id in version_ids
+            ]
+        )
+    return bool(version_ids)
+
+
+async def get_and_delete_old_versions(
+    image_name: ImageName, inputs: Inputs, http_client: AsyncClient, semaphore: Semaphore | None = None
+) -> bool:
     """
     Delete old package versions for an image name.
 
     This function contains more or less all our logic.
+
+    :param semaphore: Limits how many deletions run at the same time. Pass the same semaphore
+        for every image, to limit deletions across images.
+    :return: Whether any versions were selected for deletion.
     """
-    versions = await GithubAPI.list_package_versions(
-        account_type=inputs.account_type, org_name=inputs.org_name, image_name=image_name, http_client=http_client
-    )
+    global versions_scanned
+    if aborted:
+        return False
+    if past_deadline():
+        print(f'Skipping {image_name.value}, since the max-runtime was reached')
+        return False
+
+    if inputs.version_ids:
+        # The user already picked the versions to delete
+        return await delete_version_ids(image_name, inputs.version_ids, inputs, http_client, semaphore)
+
+    if inputs.retry_failed_from:
+        # Only retry the versions a previous run failed to delete
+        package_names = {image_name.value, unquote(image_name.encoded)}
+        version_ids = [version_id for package, version_id in inputs.retry_failed_from if package in package_names]
+        return await delete_version_ids(image_name, version_ids, inputs, http_client, semaphore)
+
+    truncated = False
+
+    def found_more_pages() -> None:
+        nonlocal truncated
+        truncated = True
+
+    # With a max-versions-scanned, images are listed one at a time, so each knows how many versions are left
+    async with versions_scanned_lock if inputs.max_versions_scanned else nullcontext():
+        max_pages = inputs.max_pages or None
+        if inputs.max_versions_scanned:
+            remaining = inputs.max_versions_scanned - versions_scanned
+            if remaining <= 0:
+                print(f'Skipping {image_name.value}, since max-versions-scanned was reached')
+                return False
+            max_pages = min(max_pages or remaining, -(-remaining // PAGE_SIZE))
+
+        with timed(RequestPhase.LIST_VERSIONS):
+            versions = await GithubAPI.list_package_versions(
+                account_type=inputs.account_type,
+                org_name=inputs.org_name,
+                image_name=image_name,
+                http_client=http_client,
+                concurrency=inputs.concurrency_per_package,
+                max_pages=max_pages,
+                package_type=inputs.package_type,
+                on_truncated=found_more_pages,
+            )
+        versions_scanned += len(versions)
+
+    version_count = len(versions)
+    stale_duplicates = find_stale_duplicates(versions) if inputs.delete_duplicate_tags else set()
+    oldest_tagged = find_oldest_tagged(versions, inputs) if inputs.untagged_older_than_tagged else None
+    if inputs.warn_tags_over:
+        # Lots of tags on one version usually means a CI job is stuck retagging it
+        for version in versions:
+            if (tag_count := len(get_image_tags(version))) > inputs.warn_tags_over:
+                print(f'Warning: image version {image_name.value}:{version["id"]} has {tag_count} tags')
+    if truncated:
+        print(
+            f'Only fetched the first {max_pages} page(s) of versions for {image_name.value}. '
+            'Older versions were not considered.'
+        )
+
+    # Cosign artifacts can only be matched to their image, when we've seen every version
+    all_versions = versions
+    find_orphans = inputs.cleanup_cosign_orphans and not truncated
 
     # Trim the version list to the n'th element we want to keep
     if inputs.keep_at_least > 0:
         versions = versions[inputs.keep_at_least :]
 
-    # Define list of deletion-tasks to append to
-    tasks = []
+    # Define list of versions to delete. We don't create deletion-tasks
+    # until we've looked at every version, so an error aborts before anything is deleted from this image.
+    versions_to_delete = []
+    decisions_by_id: dict[int, list[Decision]] = {}
+
+    # The semaphore only bounds the deletions, so selecting versions never waits for other images
+    sem = semaphore or Semaphore(inputs.parallel_deletes)
 
     # Iterate through dicts of image versions
-    sem = Semaphore(50)
+    for version in versions:
+
+        # Parse either the update-at timestamp, or the created-at timestamp
+        # depending on which on the user has specified that we should use
+        timestamp = get_relevant_timestamp(version, inputs)
+        if timestamp is None:
+            # The on-missing-updated-at policy told us to skip this version
+            continue
+        updated_or_created_at = parse(timestamp) if timestamp else None
+
+        if not updated_or_created_at:
+            print(f'Skipping image version {version["id"]}. Unable to parse timestamps.')
+            continue
+
+        decisions = [rule(version, updated_or_created_at, inputs) for rule in SELECTION_RULES]
+        if version['id'] in stale_duplicates:
+            # Stale duplicates are selected for deletion regardless of the cut-off
+            decisions.append(Decision.DELETE)
+        if oldest_tagged and not get_image_tags(version) and updated_or_created_at >= oldest_tagged:
+            # Untagged versions newer than every tagged version may be part of a push that isn't done yet
+            decisions.append(Decision.PROTECT)
+        decisions_by_id[version['id']] = decisions
+        if resolve_decisions(decisions):
+            versions_to_delete.append(version)
+
+    selected_ids = {v['id'] for v in versions_to_delete}
+
+    if inputs.keep_at_least_one and versions_to_delete and len(versions_to_delete) == version_count:
+        # Versions are listed newest first, so this keeps the most recent one
+        print(f'Keeping image version {versions_to_delete[0]["id"]}, so {image_name.value} has a version left')
+        versions_to_delete = versions_to_delete[1:]
+
+    if inputs.verify_plan and versions_to_delete:
+        versions_to_delete = await verify_plan(
+            image_name=image_name, versions=versions_to_delete, inputs=inputs, http_client=http_client
+        )
 
-    async with sem:
-        for version in versions:
+    if find_orphans:
+        # Signatures of the images we delete are deleted too, unless another rule wants to keep them. This
+        # looks at the final plan, so an image that ends up being kept never loses its signature
+        orphans = find_cosign_orphans(all_versions, {v.get('name') for v in versions_to_delete}) - selected_ids
+        orphan_versions = [
+            version
+            for version in versions
+            if version['id'] in orphans
+            and version['id'] in decisions_by_id
+            and resolve_decisions(decisions_by_id[version['id']] + [Decision.DELETE])
+        ]
+        if inputs.keep_at_least_one and orphan_versions and len(versions_to_delete + orphan_versions) == version_count:
+            # A signature is no use without its image, so we keep the newest image along with its signatures
+            kept = versions_to_delete[0] if versions_to_delete else orphan_versions[0]
+            print(f'Keeping image version {kept["id"]}, so {image_name.value} has a version left')
+            versions_to_delete = [v for v in versions_to_delete if v is not kept]
+            orphans = find_cosign_orphans(all_versions, {v.get('name') for v in versions_to_delete})
+            orphan_versions = [v for v in orphan_versions if v is not kept and v['id'] in orphans]
+        if inputs.verify_plan and orphan_versions:
+            orphan_versions = await verify_plan(
+                image_name=image_name, versions=orphan_versions, inputs=inputs, http_client=http_client
+            )
+        versions_to_delete += orphan_versions
 
-            # Parse either the update-at timestamp, or the created-at timestamp
-            # depending on which on the user has specified that we should use
-            updated_or_created_at = parse(version[inputs.timestamp_to_use.value])
-
-            if not updated_or_created_at:
-                print(f'Skipping image version {version["id"]}. Unable to parse timestamps.')
-                continue
-
-            if inputs.cut_off < updated_or_created_at:
-                # Skipping because it's above our datetime cut-off
-                # we're only looking to delete containers older than some timestamp
-                continue
-
-            # Load the tags for the individual image we're processing
-            if (
-                'metadata' in version
-                and 'container' in version['metadata']
-                and 'tags' in version['metadata']['container']
-            ):
-                image_tags = version['metadata']['container']['tags']
-            else:
-                image_tags = []
-
-            if inputs.untagged_only and image_tags:
-                # Skipping because no tagged images should be deleted
-                # We could proceed if image_tags was empty, but it's not
-                continue
-
-            if not image_tags and not inputs.filter_include_untagged:
-                # Skipping, because the filter_include_untagged setting is False
-                continue
-
-            delete_image = not inputs.filter_tags
-            for filter_tag in inputs.filter_tags:
-                # One thing to note here is that we use fnmatch to support wildcards.
-                # A filter-tags setting of 'some-tag-*' should match to both
-                # 'some-tag-1' and 'some-tag-2'.
-                if any(fnmatch(tag, filter_tag) for tag in image_tags):
-                    delete_image = True
-                    break
-
-            for skip_tag in inputs.skip_tags:
-                if any(fnmatch(tag, skip_tag) for tag in image_tags):
-                    # Skipping because this image version is tagged with a protected tag
-                    delete_image = False
-
-            if delete_image:
-                tasks.append(
-                    asyncio.create_task(
-                        GithubAPI.delete_package(
-                            account_type=inputs.account_type,
-                            org_name=inputs.org_name,
-                            image_name=image_name,
-                            version_id=version['id'],
-                            http_client=http_client,
-                            semaphore=sem,
-                        )
-                    )
+    deletion_start = monotonic()
+    if not inputs.dry_run:
+        # Deletions start in this order, which decides what's left when a run is cut short by the max-runtime
+        untagged_first = inputs.priority == DeletionPriority.UNTAGGED_FIRST
+        versions_to_delete = sorted(versions_to_delete, key=lambda v: bool(get_image_tags(v)) == untagged_first)
+        tasks = [
+            asyncio.create_task(
+                GithubAPI.delete_package(
+                    account_type=inputs.account_type,
+                    org_name=inputs.org_name,
+                    image_name=image_name,
+                    version_id=version['id'],
+                    http_client=http_client,
+                    semaphore=sem,
+                    package_type=inputs.package_type,
                 )
+            )
+            for version in versions_to_delete
+        ]
+    else:
+        # Run everything up until the deletion itself
+        for version in versions_to_delete:
+            dry_run_output(image_name=image_name, version=version)
+        tasks = []
 
-    if not tasks:
+    if not versions_to_delete and not inputs.report_only_changes:
         print(f'No more versions to delete for {image_name.value}')
 
     results = await asyncio.gather(*tasks, return_exceptions=True)
+    if tasks:
+        time_spent[RequestPhase.DELETE] += monotonic() - deletion_start
+
+    if inputs.verify_deletions and tasks:
+        reported = set(deleted)
+        await verify_deletions(
+            image_name=image_name,
+            version_ids=[v['id'] for v in versions_to_delete if f'{image_name.value}:{v["id"]}' in reported],
+            inputs=inputs,
+            http_client=http_client,
+        )
 
     for item in results:
         if isinstance(item, Exception):
@@ -369,8 +1701,115 @@ async def get_and_delete_old_versions(image_name: ImageName, inputs: Inputs, htt
                     f'Please report this at https://github.com/snok/container-retention-policy/issues/new'
                 )
 
+    return bool(versions_to_delete)
+
+
+async def with_retries(request: Callable[[], Awaitable[T]], *, attempts: int = 3, backoff: float = 1) -> T:
+    """
+    Send a request, retrying if we can't reach the Github API.
+
+    Used for the requests we send first, since on freshly started runners they're the
+    ones that run into DNS or TLS hiccups. HTTP errors are not retried.
 
-def filter_image_names(all_packages: list[PackageResponse], image_names: list[str]) -> set[ImageName]:
+    :param request: Sends the request.
+    :param attempts: How many times to try, in total.
+    :param backoff: How long to wait before the first retry. Doubles for every retry.
+    :return: What the request returned.
+    """
+    for attempt in range(1, attempts + 1):
+        try:
+            return await request()
+        except TransportError as e:
+            if attempt == attempts:
+                raise ConnectionError(f'Unable to reach the Github API after {attempts} attempts: `{e}`') from e
+            delay = backoff * 2 ** (attempt - 1)
+            print(f'Unable to reach the Github API: `{e}`. Retrying in {delay} seconds')
+            await asyncio.sleep(delay)
+
+
+async def list_packages_with_retries(
+    *, inputs: Inputs, http_client: AsyncClient, attempts: int = 3, backoff: float = 1
+) -> list[PackageResponse]:
+    """
+    List all packages, retrying if we can't reach the Github API.
+
+    :param inputs: The action inputs.
+    :param http_client: HTTP client.
+    :param attempts: How many times to try, in total.
+    :param backoff: How long to wait before the first retry. Doubles for every retry.
+    :return: List of packages.
+    """
+    return await with_retries(
+        lambda: GithubAPI.list_packages(
+            account_type=inputs.account_type,
+            org_name=inputs.org_name,
+            http_client=http_client,
+            package_type=inputs.package_type,
+        ),
+        attempts=attempts,
+        backoff=backoff,
+    )
+
+
+def filter_recently_updated(
+    all_packages: list[PackageResponse], image_names: set[ImageName], within: timedelta | None
+) -> set[ImageName]:
+    """
+    Skip packages updated within the skip-if-updated-within, since they may be in the middle of a push.
+    """
+    if within is None:
+        return image_names
+    updated_at = {package.name: package.updated_at for package in all_packages}
+    cut_off = datetime.now(timezone.utc) - within
+    skipped = {image_name for image_name in image_names if updated_at[unquote(image_name.encoded)] > cut_off}
+    for image_name in sorted(skipped):
+        print(f'Skipping {image_name.value}, since it was updated within the last {within}')
+    return image_names - skipped
+
+
+def filter_visibility(all_packages: list[PackageResponse], visibility: VisibilityFilter) -> list[PackageResponse]:
+    """
+    Filter packages by the only-visibility action input.
+
+    Packages with a missing or unknown visibility are left out, since they might be public.
+    """
+    if visibility == VisibilityFilter.ALL:
+        return all_packages
+    packages = []
+    for package in all_packages:
+        if package.visibility not in ('public', 'private', 'internal'):
+            print(f'Warning: skipping package {package.name}, since its visibility is unknown')
+        elif (package.visibility == 'public') == (visibility == VisibilityFilter.PUBLIC):
+            packages.append(package)
+    return packages
+
+
+def filter_package_name_regex(
+    all_packages: list[PackageResponse],
+    include: re.Pattern[str] | None,
+    exclude: re.Pattern[str] | None,
+    strip_prefix: str = '',
+) -> list[PackageResponse]:
+    """
+    Filter packages by the package-name-regex and package-name-regex-exclude action inputs.
+
+    This runs before the image-names are matched, so a package has to match both to be handled.
+    The exclude regex wins over everything else.
+    """
+    packages = []
+    for package in all_packages:
+        short_name = package.name.removeprefix(strip_prefix)
+        if include and not include.search(short_name):
+            continue
+        if exclude and exclude.search(short_name):
+            continue
+        packages.append(package)
+    return packages
+
+
+def filter_image_names(
+    all_packages: list[PackageResponse], image_names: list[str], strip_prefix: str = ''
+) -> set[ImageName]:
     """
     Filter package names by action input package names.
 
@@ -379,8 +1818,12 @@ def filter_image_names(all_packages: list[PackageResponse], image_names: list[st
     The idea is that given a list: ['ab', 'ac', 'bb', 'ba'], and image names (from the action inputs): ['aa', 'b*'],
     this function should return ['ba', 'bb'].
 
+    If a prefix to strip is given, it's removed from the package names before matching, and from the names
+    we log. The url-encoded names always hold the full package name, since that's what the Github API knows.
+
     :param all_packages: List of packages received from the Github API
     :param image_names: List of image names the client wishes to delete from
+    :param strip_prefix: Prefix to remove from package names, for matching and display
     :return: The intersection of the two lists, returned as `ImageName` instances
     """
 
@@ -390,14 +1833,41 @@ def filter_image_names(all_packages: list[PackageResponse], image_names: list[st
     # contained in the users/orgs list of packages.
     for image_name in image_names:
         for package in all_packages:
-            if fnmatch(package.name, image_name):
+            short_name = package.name.removeprefix(strip_prefix)
+            if fnmatch(short_name, image_name):
                 packages_to_delete_from.add(
-                    ImageName(package.name.strip(), quote_from_bytes(package.name.strip().encode('utf-8'), safe=''))
+                    ImageName(short_name.strip(), quote_from_bytes(package.name.strip().encode('utf-8'), safe=''))
                 )
 
+    # Our outputs and the retry-failed-from and pushed-manifest inputs name images by their short name,
+    # so two packages with the same short name can't be told apart
+    packages_by_short_name: dict[str, list[str]] = {}
+    for package_name in sorted(packages_to_delete_from):
+        packages_by_short_name.setdefault(package_name.value, []).append(unquote(package_name.encoded))
+    for short_name, package_names in packages_by_short_name.items():
+        if len(package_names) > 1:
+            raise ValueError(
+                f'The packages {" and ".join(package_names)} are both named {short_name} after stripping '
+                f'the prefix {strip_prefix}. Narrow down the image-names, or change the strip-prefix'
+            )
+
     return packages_to_delete_from
 
 
+def set_action_outputs() -> None:
+    """
+    Add the image versions we handled to the action outputs, as comma-separated lists.
+    """
+    for name, l in [
+        ('needs-github-assistance', needs_github_assistance),
+        ('deleted', deleted),
+        ('failed', [failure.image_name_with_tag for failure in failed]),
+        ('unverified', unverified_deletions),
+    ]:
+        comma_separated_list = ','.join(l)
+        print(f'::set-output name={name}::{comma_separated_list}')
+
+
 async def main(
     account_type: str,
     org_name: str,
@@ -410,6 +1880,51 @@ async def main(
     keep_at_least: str,
     filter_tags: str,
     filter_include_untagged: str,
+    on_missing_updated_at: str,
+    dry_run: str,
+    concurrency_per_package: str,
+    report_only_changes: str,
+    all_packages: str,
+    keep_at_least_one: str,
+    untagged_cut_off: str,
+    dry_run_nonempty_exit_code: str,
+    max_pages: str,
+    strip_prefix: str,
+    delete_duplicate_tags: str,
+    protect_last_releases: str,
+    min_rate_limit: str,
+    explain_rate_limit: str,
+    package_type: str,
+    max_runtime: str,
+    protect_active_branches: str,
+    print_config: str,
+    version_ids: str,
+    parallel_deletes: str,
+    max_versions_scanned: str,
+    token_file: str,
+    exclude_created_today: str,
+    only_visibility: str,
+    verify_plan: str,
+    on_secondary_rate_limit: str,
+    package_name_regex: str,
+    package_name_regex_exclude: str,
+    timing: str,
+    tag_cut_offs: str,
+    confirm: str,
+    packages_only: str,
+    retry_failed_from: str,
+    filter_version_names: str,
+    on_empty_account: str,
+    verify_deletions: str,
+    warn_tags_over: str,
+    cleanup_cosign_orphans: str,
+    skip_if_updated_within: str,
+    match_short_digest: str,
+    halt_on_auth_errors: str,
+    priority: str,
+    pushed_manifest: str,
+    untagged_older_than_tagged: str,
+    circuit_breaker_threshold: str,
 ) -> None:
     """
     Delete old image versions.
@@ -436,7 +1951,57 @@ async def main(
     :param filter_tags: Comma-separated list of tags to consider for deletion.
         Supports wildcard '*', '?', '[seq]' and '[!seq]' via Unix shell-style wildcards
     :param filter_include_untagged: Whether to consider untagged images for deletion.
+    :param on_missing_updated_at: What to do with image versions without an updated_at timestamp,
+        when timestamp-to-use is 'updated_at'. Can be 'fallback', 'skip', or 'error'.
+    :param dry_run: Whether to only output the image versions we would delete, without deleting them.
+    :param concurrency_per_package: How many pages of image versions to fetch at the same time, per image.
+    :param report_only_changes: Whether to summarize images without versions to delete in one line,
+        instead of logging each of them.
+    :param all_packages: Whether to delete from every package in the account. Can't be combined with image_names.
+    :param keep_at_least_one: Whether to always keep the most recent version of an image, regardless of other inputs.
+    :param untagged_cut_off: The cut-off to use for untagged images. Defaults to the cut-off.
+    :param dry_run_nonempty_exit_code: The exit code to use when a dry run would have deleted something.
+    :param max_pages: How many pages of image versions to fetch at most, per image. 0 means no limit.
+    :param strip_prefix: A prefix to remove from package names, before matching them to image names and logging them.
+    :param delete_duplicate_tags: Whether to delete older versions whose tags are all held by a newer version,
+        regardless of the cut-off.
+    :param protect_last_releases: How many of the repository's latest releases to protect the image digests of.
+    :param min_rate_limit: How many requests must be left in the rate limit to start a run. 0 means no check.
+    :param explain_rate_limit: Whether to print how many requests were sent, and what's left of the rate limit.
+    :param package_type: The type of packages to clean up: container, or the legacy docker packages.
+    :param max_runtime: How long to run for, before leaving the remaining deletions for the next run.
+    :param protect_active_branches: Whether to protect versions tagged branch-<name>, for the repository's branches.
+    :param print_config: Whether to print the parsed inputs before starting.
+    :param version_ids: IDs of image versions to delete, skipping all selection logic.
+    :param parallel_deletes: How many image versions to delete at the same time, across all images.
+    :param max_versions_scanned: How many image versions to list at most, across all images. 0 means no limit.
+    :param token_file: Path to a file to read the token from, instead of passing the token itself.
+    :param exclude_created_today: Whether to protect versions created on the current UTC day.
+    :param only_visibility: Which packages to clean up, by visibility: all, public or private.
+    :param verify_plan: Whether to check that versions haven't changed since listing, before deleting them.
+    :param on_secondary_rate_limit: Whether to retry later, or abort the run, when the secondary rate limit is hit.
+    :param package_name_regex: A regex package names must match, on top of the image names.
+    :param package_name_regex_exclude: A regex for package names to never handle.
+    :param timing: Whether to print how long each phase of the run took.
+    :param tag_cut_offs: Semicolon-separated list of <pattern>:<cut-off> pairs, to use other cut-offs for matching tags.
+    :param confirm: Must match the RETENTION_POLICY_CONFIRMATION environment variable, if set, to delete anything.
+    :param packages_only: Whether to only print the matching packages, without listing or deleting any versions.
+    :param retry_failed_from: Path to a file with the failed output of a previous run, to retry deleting those versions.
+    :param filter_version_names: Comma-separated list of version names (digests) to consider for deletion.
+    :param on_empty_account: Whether to do nothing, warn, or fail, when the account has no packages.
+    :param verify_deletions: Whether to check that deleted image versions are really gone.
+    :param warn_tags_over: Warn about image versions with more tags than this.
+    :param cleanup_cosign_orphans: Whether to delete cosign signatures and attestations of images that are gone.
+    :param skip_if_updated_within: An ISO 8601 duration. Packages updated more recently than this are skipped.
+    :param match_short_digest: Whether filter-tags and skip-tags should also match the short digest of a version.
+    :param halt_on_auth_errors: Whether to abort the run, when several deletions in a row fail with an auth error.
+    :param priority: Whether to delete the untagged or the tagged versions of an image first.
+    :param pushed_manifest: Path to a JSON file listing the images a build pushed, to scope the run to and protect.
+    :param untagged_older_than_tagged: Whether to only delete untagged versions older than the oldest tagged version.
+    :param circuit_breaker_threshold: How many deletions in a row may fail, before the run is aborted.
     """
+    run_start = monotonic()
+    global deadline, abort_on_secondary_rate_limit, abort_on_auth_errors, max_consecutive_failures
     inputs = Inputs(
         image_names=image_names,
         account_type=account_type,
@@ -448,26 +2013,195 @@ async def main(
         keep_at_least=keep_at_least,
         filter_tags=filter_tags,
         filter_include_untagged=filter_include_untagged,
+        on_missing_updated_at=on_missing_updated_at,
+        dry_run=dry_run,
+        concurrency_per_package=concurrency_per_package,
+        report_only_changes=report_only_changes,
+        all_packages=all_packages,
+        keep_at_least_one=keep_at_least_one,
+        untagged_cut_off=untagged_cut_off,
+        dry_run_nonempty_exit_code=dry_run_nonempty_exit_code,
+        max_pages=max_pages,
+        strip_prefix=strip_prefix,
+        delete_duplicate_tags=delete_duplicate_tags,
+        protect_last_releases=protect_last_releases,
+        min_rate_limit=min_rate_limit,
+        explain_rate_limit=explain_rate_limit,
+        package_type=package_type,
+        max_runtime=max_runtime,
+        protect_active_branches=protect_active_branches,
+        print_config=print_config,
+        version_ids=version_ids,
+        parallel_deletes=parallel_deletes,
+        max_versions_scanned=max_versions_scanned,
+        exclude_created_today=exclude_created_today,
+        only_visibility=only_visibility,
+        verify_plan=verify_plan,
+        on_secondary_rate_limit=on_secondary_rate_limit,
+        package_name_regex=package_name_regex,
+        package_name_regex_exclude=package_name_regex_exclude,
+        timing=timing,
+        tag_cut_offs=tag_cut_offs,
+        confirm=confirm,
+        packages_only=packages_only,
+        retry_failed_from=retry_failed_from,
+        filter_version_names=filter_version_names,
+        on_empty_account=on_empty_account,
+        verify_deletions=verify_deletions,
+        warn_tags_over=warn_tags_over,
+        cleanup_cosign_orphans=cleanup_cosign_orphans,
+        skip_if_updated_within=skip_if_updated_within,
+        match_short_digest=match_short_digest,
+        halt_on_auth_errors=halt_on_auth_errors,
+        priority=priority,
+        pushed_manifest=pushed_manifest,
+        untagged_older_than_tagged=untagged_older_than_tagged,
+        circuit_breaker_threshold=circuit_breaker_threshold,
     )
+    abort_on_secondary_rate_limit = inputs.on_secondary_rate_limit == SecondaryRateLimitPolicy.ABORT
+    abort_on_auth_errors = inputs.halt_on_auth_errors
+    max_consecutive_failures = inputs.circuit_breaker_threshold
+    if token_file:
+        if token:
+            raise ValueError('token cannot be combined with token-file')
+        token = Path(token_file).read_text().strip()
+    if not token:
+        raise ValueError('token is required, unless token-file is set')
+
+    expected_confirmation = environ.get('RETENTION_POLICY_CONFIRMATION')
+    if expected_confirmation and not inputs.dry_run and inputs.confirm != expected_confirmation:
+        raise ValueError(
+            'Refusing to delete anything, since confirm does not match the RETENTION_POLICY_CONFIRMATION '
+            'environment variable. Set confirm, or enable dry-run'
+        )
+
+    if inputs.print_config:
+        print_resolved_config(inputs)
+    if inputs.max_runtime is not None:
+        deadline = monotonic() + inputs.max_runtime.total_seconds()
+
     async with AsyncClient(
         headers={'accept': 'application/vnd.github.v3+json', 'Authorization': f'Bearer {token}'}
     ) as client:
+        if inputs.min_rate_limit or inputs.explain_rate_limit:
+            # Requests to the rate limit endpoint are free, so we can look before we start
+            starting_rate_limit = await with_retries(lambda: get_rate_limit(http_client=client))
+
+        if inputs.min_rate_limit:
+            # Skip the run, rather than running out of requests halfway through it
+            remaining = starting_rate_limit['remaining']
+            if remaining < inputs.min_rate_limit:
+                print(
+                    f'Skipping this run: only {remaining} requests left in the rate limit, need {inputs.min_rate_limit}'
+                )
+                # Later steps may read the outputs, so they're set even though they're empty
+                set_action_outputs()
+                return
+
+        if inputs.account_type == AccountType.AUTO:
+            # Default to the owner of the repository the workflow runs in
+            owner = inputs.org_name or environ.get('GITHUB_REPOSITORY_OWNER')
+            if not owner:
+                raise ValueError('Unable to detect the account type. Set account-type, or org-name')
+            inputs.account_type = await with_retries(lambda: detect_account_type(owner=owner, http_client=client))
+            inputs.org_name = owner if inputs.account_type == AccountType.ORG else None
+            print(f'Detected account type {inputs.account_type.value} for {owner}')
+            if inputs.account_type == AccountType.PERSONAL:
+                # Personal packages are listed for whoever owns the token, which has to be the account we detected
+                login = await with_retries(lambda: get_authenticated_user(http_client=client))
+                if login.lower() != owner.lower():
+                    raise ValueError(
+                        f'{owner} is a personal account, but the token belongs to {login}. '
+                        f'Use a token of {owner}, or set account-type'
+                    )
+
         # Get all packages from the user or orgs account
-        all_packages = await GithubAPI.list_packages(
-            account_type=inputs.account_type, org_name=inputs.org_name, http_client=client
-        )
+        with timed(RequestPhase.LIST_PACKAGES):
+            all_packages = await list_packages_with_retries(inputs=inputs, http_client=client)
+
+        if not all_packages and inputs.on_empty_account != EmptyAccountPolicy.OK:
+            # An account without any packages is more often than not the wrong account
+            account = inputs.org_name or 'the authenticated user'
+            msg = f'Found no {inputs.package_type.value} packages for {account}. Check the account-type and org-name'
+            if inputs.on_empty_account == EmptyAccountPolicy.ERROR:
+                raise ValueError(msg)
+            print(f'Warning: {msg}')
+
+        if inputs.protect_last_releases:
+            # Collect the digests our releases point to, so the release_digests_rule can protect them
+            digests = await list_release_digests(
+                repository=environ['GITHUB_REPOSITORY'], count=inputs.protect_last_releases, http_client=client
+            )
+            print(f'Protecting {len(digests)} image digest(s) referenced by the latest releases')
+            protected_digests.update(digests)
 
-        # Filter existing image names by action inputs
-        packages_to_delete_from = filter_image_names(all_packages, inputs.image_names)
+        if inputs.pushed_manifest:
+            # Whatever the build just pushed is never deleted, even if it's past the cut-off
+            protected_digests.update(image.digest for image in inputs.pushed_manifest)
+            print(f'Protecting {len(inputs.pushed_manifest)} image digest(s) from the pushed-manifest')
+
+        if inputs.protect_active_branches:
+            branch_tags = await list_branch_tags(repository=environ['GITHUB_REPOSITORY'], http_client=client)
+            print(f'Protecting images tagged for {len(branch_tags)} active branch(es)')
+            protected_branch_tags.update(branch_tags)
 
-        # Create tasks to run concurrently
+        # Filter existing image names by action inputs
+        image_names = ['*'] if inputs.all_packages else inputs.image_names
+        all_packages = filter_visibility(all_packages, inputs.only_visibility)
+        all_packages = filter_package_name_regex(
+            all_packages, inputs.package_name_regex, inputs.package_name_regex_exclude, inputs.strip_prefix
+        )
+        packages_to_delete_from = filter_image_names(all_packages, image_names, inputs.strip_prefix)
+        packages_to_delete_from = filter_recently_updated(
+            all_packages, packages_to_delete_from, inputs.skip_if_updated_within
+        )
+        if inputs.pushed_manifest:
+            # Only clean up the packages the build pushed to. We don't scope by the pushed tags, since a tag only
+            # points to one version of a package, and after the push that's the version we protect
+            pushed_packages = {image.package for image in inputs.pushed_manifest}
+            packages_to_delete_from = {
+                image_name
+                for image_name in packages_to_delete_from
+                if image_name.value in pushed_packages or unquote(image_name.encoded) in pushed_packages
+            }
+
+        if inputs.retry_failed_from:
+            # Versions of packages we don't handle would otherwise be dropped without a word
+            package_names = {image_name.value for image_name in packages_to_delete_from} | {
+                unquote(image_name.encoded) for image_name in packages_to_delete_from
+            }
+            if unmatched := [f'{p}:{v}' for p, v in inputs.retry_failed_from if p not in package_names]:
+                print(f'Warning: not retrying {", ".join(unmatched)}, since the packages don\'t match the image-names')
+
+        if inputs.packages_only:
+            print_packages(all_packages, packages_to_delete_from)
+            return
+
+        # Create tasks to run concurrently. Sorting the packages keeps the order
+        # of tasks, and with that the order of our logs, the same between runs
+        delete_semaphore = Semaphore(inputs.parallel_deletes)
         tasks = [
-            asyncio.create_task(get_and_delete_old_versions(image_name, inputs, client))
-            for image_name in packages_to_delete_from
+            asyncio.create_task(get_and_delete_old_versions(image_name, inputs, client, delete_semaphore))
+            for image_name in sorted(packages_to_delete_from)
         ]
 
         # Execute tasks
-        await asyncio.gather(*tasks)
+        try:
+            results = await asyncio.gather(*tasks)
+        except Exception:
+            # Stop the other images too, so an error doesn't leave them deleting in the background
+            for task in tasks:
+                task.cancel()
+            raise
+
+        if inputs.dry_run:
+            print_dry_run_output()
+
+        if inputs.explain_rate_limit:
+            print_rate_limit_summary(starting_rate_limit, await get_rate_limit(http_client=client))
+
+    if inputs.report_only_changes:
+        print(f'No more versions to delete for {results.count(False)} of {len(results)} image(s)')
 
     if needs_github_assistance:
         # Print a human readable list of public images we couldn't handle
@@ -483,14 +2217,37 @@ async def main(
         print(msg)
         print('─' * 110)
 
+    if failed:
+        # Print the images we failed to delete, grouped by what went wrong
+        print(f'\nFailed to delete {len(failed)} image version(s):')
+        for cause in FailureCause:
+            if failures := [f.image_name_with_tag for f in failed if f.cause == cause]:
+                print(f'\t- {cause.value}: {", ".join(failures)}')
+
+    if inputs.verify_deletions and deleted and not inputs.dry_run:
+        print(f'\nVerified that {len(verified_deletions)} of {len(deleted)} deleted image version(s) are gone')
+        if unverified_deletions:
+            print(f'\t- still exist: {", ".join(unverified_deletions)}')
+
+    if inputs.timing:
+        print_timing_summary(monotonic() - run_start)
+
+    if skipped_by_deadline:
+        print(
+            f'\nReached the max-runtime. {len(skipped_by_deadline)} image version(s) were not deleted, '
+            'and are left for the next run'
+        )
+
     # Then add it to the action outputs
-    for name, l in [
-        ('needs-github-assistance', needs_github_assistance),
-        ('deleted', deleted),
-        ('failed', failed),
-    ]:
-        comma_separated_list = ','.join(l)
-        print(f'::set-output name={name}::{comma_separated_list}')
+    set_action_outputs()
+
+    if aborted:
+        print(f'Aborted the run, since {abort_reason}')
+        raise SystemExit(1)
+
+    if inputs.dry_run and inputs.dry_run_nonempty_exit_code and any(results):
+        print(f'Exiting with code {inputs.dry_run_nonempty_exit_code}, since the dry run would have deleted images')
+        raise SystemExit(inputs.dry_run_nonempty_exit_code)
 
 
 if __name__ == '__main__':
@@ -1,27 +1,56 @@
 import asyncio
+import json
 from asyncio import Semaphore
+from collections import Counter
 from copy import deepcopy
 from datetime import datetime, timedelta, timezone
 from functools import partial
 from unittest.mock import AsyncMock, Mock
+from urllib.parse import parse_qs, urlparse
 
 import pytest as pytest
-from httpx import AsyncClient
+from httpx import AsyncClient, ConnectError
 from pydantic import ValidationError
 
 import main
 from main import (
     AccountType,
+    Decision,
+    DeleteFailure,
+    FailureCause,
     ImageName,
     Inputs,
     PackageResponse,
+    PackageType,
+    VisibilityFilter,
+    active_branches_rule,
+    created_today_rule,
+    cut_off_rule,
     delete_org_package_versions,
     delete_package_versions,
+    detect_account_type,
     filter_image_names,
+    filter_include_untagged_rule,
+    filter_package_name_regex,
+    filter_tags_rule,
+    filter_version_names_rule,
+    filter_visibility,
     get_and_delete_old_versions,
+    get_authenticated_user,
+    get_image_tags,
+    get_org_package_version,
+    list_branch_tags,
     list_org_package_versions,
+    list_org_packages,
     list_package_versions,
+    list_packages_with_retries,
+    list_release_digests,
+    release_digests_rule,
+    resolve_decisions,
+    skip_tags_rule,
+    untagged_only_rule,
 )
+from main import list_package_versions as list_package_versions_
 from main import main as main_
 from main import post_deletion_output
 
@@ -30,6 +59,7 @@ mock_response.json.return_value = []
 mock_response.is_error = False
 mock_bad_response = Mock()
 mock_bad_response.is_error = True
+mock_bad_response.status_code = 500
 mock_http_client = AsyncMock()
 mock_http_client.get.return_value = mock_response
 mock_http_client.delete.return_value = mock_response
@@ -45,6 +75,76 @@ async def test_list_package_version():
     await list_package_versions(image_name=ImageName('test', 'test'), http_client=mock_http_client)
 
 
+class PaginatedHttpClient:
+    """
+    Serves `total` items, 100 per page, and keeps track of how many pages are requested at the same time.
+    """
+
+    def __init__(self, total):
+        self.total = total
+        self.in_flight = 0
+        self.max_in_flight = 0
+        self.requests = 0
+        self.urls = []
+
+    async def get(self, url):
+        self.requests += 1
+        self.urls.append(url)
+        self.in_flight += 1
+        self.max_in_flight = max(self.max_in_flight, self.in_flight)
+        await asyncio.sleep(0.01)
+        self.in_flight -= 1
+        page = int(parse_qs(urlparse(url).query)['page'][0])
+        response = Mock()
+        response.links = {'next': {'url': url}} if page * 100 < self.total else {}
+        timestamp = '2021-05-26T14:03:03Z'
+        response.json.return_value = [
+            {'id': i, 'name': f'package-{i}', 'created_at': timestamp, 'updated_at': timestamp}
+            for i in range((page - 1) * 100, min(page * 100, self.total))
+        ]
+        return response
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('concurrency', [1, 2, 4])
+async def test_list_package_versions_pagination(concurrency):
+    http_client = PaginatedHttpClient(total=250)
+    versions = await list_package_versions(
+        image_name=ImageName('test', 'test'), http_client=http_client, concurrency=concurrency
+    )
+    assert [v['id'] for v in versions] == list(range(250))
+    assert http_client.max_in_flight == concurrency
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('concurrency', [1, 2, 4])
+async def test_list_package_versions_max_pages(concurrency):
+    http_client = PaginatedHttpClient(total=1000)
+    versions = await list_package_versions(
+        image_name=ImageName('test', 'test'), http_client=http_client, concurrency=concurrency, max_pages=3
+    )
+    assert [v['id'] for v in versions] == list(range(300))
+    assert http_client.requests == 3
+
+
+@pytest.mark.asyncio
+async def test_list_org_packages_pagination():
+    http_client = PaginatedHttpClient(total=150)
+    packages = await list_org_packages(org_name='org', http_client=http_client)
+    assert [p.id for p in packages] == list(range(150))
+    assert http_client.urls == [
+        f'https://api.github.com/orgs/org/packages?package_type=container&per_page=100&page={page}' for page in [1, 2]
+    ]
+
+
+@pytest.mark.asyncio
+async def test_list_packages_pagination():
+    http_client = PaginatedHttpClient(total=150)
+    packages = await main.list_packages(http_client=http_client)
+    assert [p.id for p in packages] == list(range(150))
+    assert http_client.requests == 2
+
+
 @pytest.mark.asyncio
 async def test_delete_org_package_version():
     await delete_org_package_versions(
@@ -63,6 +163,56 @@ async def test_delete_package_version():
     )
 
 
+@pytest.mark.asyncio
+async def test_docker_package_type_urls():
+    http_client = AsyncMock()
+    http_client.get.return_value = mock_response
+    http_client.delete.return_value = mock_response
+    image_name = ImageName('test', 'test')
+    docker = PackageType.DOCKER
+
+    await list_org_packages(org_name='org', http_client=http_client, package_type=docker)
+    url = http_client.get.call_args.args[0]
+    assert url.startswith('https://api.github.com/orgs/org/packages?package_type=docker&')
+    await list_org_package_versions(org_name='org', image_name=image_name, http_client=http_client, package_type=docker)
+    url = http_client.get.call_args.args[0]
+    assert url.startswith('https://api.github.com/orgs/org/packages/docker/test/versions?')
+    await delete_package_versions(
+        image_name=image_name, http_client=http_client, version_id=123, semaphore=Semaphore(1), package_type=docker
+    )
+    assert http_client.delete.call_args.args[0] == 'https://api.github.com/user/packages/docker/test/versions/123'
+
+
+@pytest.mark.asyncio
+async def test_get_org_package_version_missing():
+    http_client = AsyncMock()
+    http_client.get.return_value = Mock()
+    http_client.get.return_value.status_code = 404
+    version = await get_org_package_version(
+        org_name='org', image_name=ImageName('test', 'test'), version_id=123, http_client=http_client
+    )
+    assert version is None
+    assert http_client.get.call_args.args[0] == 'https://api.github.com/orgs/org/packages/container/test/versions/123'
+
+
+def test_get_image_tags():
+    assert get_image_tags({'metadata': {'container': {'tags': ['latest']}}}) == ['latest']
+    assert get_image_tags({'metadata': {'docker': {'tag': ['latest']}}}) == ['latest']
+    assert get_image_tags({'metadata': {'package_type': 'container'}}) == []
+
+
+@pytest.mark.asyncio
+async def test_delete_package_version_past_deadline(mocker):
+    mocker.patch.object(main, 'deadline', 0)
+    mocker.patch.object(main, 'skipped_by_deadline', [])
+    http_client = AsyncMock()
+    await delete_package_versions(
+        image_name=ImageName('test', 'test'), http_client=http_client, version_id=123, semaphore=Semaphore(1)
+    )
+    assert not http_client.delete.called
+    assert main.skipped_by_deadline == ['test:123']
+
+
 @pytest.mark.asyncio
 async def test_delete_package_version_semaphore():
     """
@@ -100,6 +250,32 @@ def test_post_deletion_output(capsys):
     assert captured.out != 'Deleted old image: test:123\n'
 
 
+def test_post_deletion_output_failure_reason(mocker):
+    mocker.patch.object(main, 'failed', [])
+    response = Mock()
+    response.is_error = True
+    response.status_code = 403
+    response.json.return_value = {'message': 'Must have admin rights to Repository.'}
+    post_deletion_output(response=response, image_name=ImageName('test', 'test'), version_id=123)
+    assert main.failed == [DeleteFailure('test', 123, 403, 'Must have admin rights to Repository.')]
+    assert main.failed[0].cause == FailureCause.AUTH
+
+
+@pytest.mark.parametrize(
+    'status,message,cause',
+    [
+        (None, 'timed out', FailureCause.TRANSIENT),
+        (502, 'Bad gateway', FailureCause.TRANSIENT),
+        (429, 'Too many requests', FailureCause.RATE_LIMIT),
+        (403, 'You have exceeded a secondary rate limit.', FailureCause.RATE_LIMIT),
+        (401, 'Bad credentials', FailureCause.AUTH),
+        (404, 'Not found', FailureCause.OTHER),
+    ],
+)
+def test_delete_failure_cause(status, message, cause):
+    assert DeleteFailure('test', 123, status, message).cause == cause
+
+
 input_defaults = {
     'image_names': 'a,b',
     'cut_off': 'an hour ago utc',
@@ -122,6 +298,19 @@ def _create_inputs_model(**kwargs):
     return Inputs(**(input_defaults | kwargs))
 
 
+@pytest.mark.parametrize(
+    'org_name',
+    ['acme', '@acme', ' acme ', 'https://github.com/acme', 'github.com/orgs/acme', 'https://github.com/orgs/acme/'],
+)
+def test_org_name_normalization(capsys, org_name):
+    assert _create_inputs_model(account_type='org', org_name=org_name).org_name == 'acme'
+    captured = capsys.readouterr()
+    if org_name == 'acme':
+        assert captured.out == ''
+    else:
+        assert captured.out == f"Using 'acme' as the org-name, rather than '{org_name}'\n"
+
+
 def test_org_name_empty():
     with pytest.raises(ValidationError):
         Inputs(**(input_defaults | {'account_type': 'org', 'org_name': ''}))
@@ -229,6 +418,358 @@ class TestGetAndDeleteOldVersions:
         captured = capsys.readouterr()
         assert captured.out == 'No more versions to delete for a\n'
 
+    @pytest.mark.asyncio
+    async def test_keep_at_least_counts_tagged_and_untagged(self, mocker, capsys):
+        old = '2021-05-26T14:03:03Z'
+        data = [
+            {'id': 3, 'created_at': old, 'updated_at': old, 'metadata': {'container': {'tags': []}}},
+            {'id': 2, 'created_at': old, 'updated_at': old, 'metadata': {'container': {'tags': ['v2']}}},
+            {'id': 1, 'created_at': old, 'updated_at': old, 'metadata': {'container': {'tags': []}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(keep_at_least=2)
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == 'Deleted old image: a:1\n'
+
+    @pytest.mark.asyncio
+    async def test_keep_at_least_one(self, mocker, capsys):
+        data = deepcopy(self.valid_data) + [deepcopy(self.valid_data[0]) | {'id': 7654321}]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(keep_at_least_one='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == 'Keeping image version 1234567, so a has a version left\nDeleted old image: a:7654321\n'
+
+    @pytest.mark.asyncio
+    async def test_keep_at_least_one_not_emptied(self, mocker, capsys):
+        data = deepcopy(self.valid_data) + [{'created_at': str(datetime.now(timezone.utc)), 'id': 7654321}]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(keep_at_least_one='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == 'Deleted old image: a:1234567\n'
+
+    @pytest.mark.asyncio
+    async def test_untagged_cut_off(self, mocker, capsys):
+        two_days_ago = str(datetime.now(timezone.utc) - timedelta(days=2))
+        data = [
+            {'id': 1, 'created_at': two_days_ago, 'metadata': {'container': {'tags': ['v1']}}},
+            {'id': 2, 'created_at': two_days_ago, 'metadata': {'container': {'tags': []}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(cut_off='1 week ago UTC', untagged_cut_off='1 day ago UTC')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == 'Deleted old image: a:2\n'
+
+    @pytest.mark.asyncio
+    @pytest.mark.parametrize('total, truncated', [(150, True), (100, False)])
+    async def test_max_pages(self, mocker, capsys, total, truncated):
+        http_client = PaginatedHttpClient(total=total)
+
+        async def list_package_versions(*, image_name, max_pages, on_truncated, **kwargs):
+            versions = await list_package_versions_(
+                image_name=image_name, http_client=http_client, max_pages=max_pages, on_truncated=on_truncated
+            )
+            return [deepcopy(self.valid_data[0]) | version for version in versions]
+
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', list_package_versions)
+        inputs = _create_inputs_model(max_pages='1', filter_include_untagged='false')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        # Exactly one full page of versions is all there is, and not a sign that we missed some
+        warning = 'Only fetched the first 1 page(s) of versions for a. Older versions were not considered.\n'
+        assert captured.out == (warning if truncated else '') + 'No more versions to delete for a\n'
+
+    @pytest.mark.asyncio
+    async def test_delete_duplicate_tags(self, mocker, capsys):
+        now = str(datetime.now(timezone.utc))
+        data = [
+            {'id': 3, 'created_at': now, 'metadata': {'container': {'tags': ['latest']}}},
+            {'id': 2, 'created_at': now, 'metadata': {'container': {'tags': ['latest']}}},
+            {'id': 1, 'created_at': now, 'metadata': {'container': {'tags': ['latest', 'v1']}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(delete_duplicate_tags='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == 'Deleted old image: a:2\n'
+
+    @pytest.mark.asyncio
+    async def test_delete_duplicate_tags_skip_tags(self, mocker, capsys):
+        now = str(datetime.now(timezone.utc))
+        data = [
+            {'id': 2, 'created_at': now, 'metadata': {'container': {'tags': ['latest']}}},
+            {'id': 1, 'created_at': now, 'metadata': {'container': {'tags': ['latest']}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(delete_duplicate_tags='true', skip_tags='latest')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == 'No more versions to delete for a\n'
+
+    @pytest.mark.asyncio
+    @pytest.mark.parametrize('dry_run', ['true', 'false'])
+    async def test_version_ids(self, mocker, capsys, dry_run):
+        mocked_list = mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock())
+        mocked_delete = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+        inputs = _create_inputs_model(image_names='a', version_ids='1,2', dry_run=dry_run)
+        assert await get_and_delete_old_versions(ImageName('a', 'a'), inputs, mock_http_client) is True
+        main.print_dry_run_output()
+        assert not mocked_list.called
+        if dry_run == 'true':
+            assert capsys.readouterr().out == 'Would delete image a:1\nWould delete image a:2\n'
+            assert not mocked_delete.called
+        else:
+            assert [call.kwargs['version_id'] for call in mocked_delete.call_args_list] == [1, 2]
+
+    @pytest.mark.asyncio
+    async def test_retry_failed_from_full_package_name(self, mocker, tmp_path):
+        mocked_delete = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+        failure_file = tmp_path / 'failed.txt'
+        failure_file.write_text('foo:1,service-foo:2,bar:3')
+        inputs = _create_inputs_model(retry_failed_from=str(failure_file), strip_prefix='service-')
+        await get_and_delete_old_versions(ImageName('foo', 'service-foo'), inputs, mock_http_client)
+        assert [call.kwargs['version_id'] for call in mocked_delete.call_args_list] == [1, 2]
+
+    @pytest.mark.asyncio
+    async def test_verify_deletions(self, mocker, capsys):
+        data = [
+            {'id': 1, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': []}}},
+            {'id': 2, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': []}}},
+            {'id': 3, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': []}}},
+        ]
+        mocker.patch.object(main, 'deleted', [])
+        mocker.patch.object(main, 'verified_deletions', [])
+        mocker.patch.object(main, 'unverified_deletions', [])
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+
+        async def delete_package(*, image_name, version_id, **kwargs):
+            # Version 3 fails to delete, so there is nothing to verify for it
+            if version_id != 3:
+                main.deleted.append(f'{image_name.value}:{version_id}')
+
+        mocker.patch.object(main.GithubAPI, 'delete_package', delete_package)
+        current = {1: None, 2: data[1]}
+        mocked_get = mocker.patch.object(
+            main.GithubAPI, 'get_package_version', AsyncMock(side_effect=lambda **kwargs: current[kwargs['version_id']])
+        )
+        inputs = _create_inputs_model(verify_deletions='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        assert [c.kwargs['version_id'] for c in mocked_get.call_args_list] == [1, 2]
+        assert main.verified_deletions == ['a:1']
+        assert main.unverified_deletions == ['a:2']
+        assert 'Image version a:2 still exists, after Github reported it as deleted' in capsys.readouterr().out
+
+    @pytest.mark.asyncio
+    async def test_warn_tags_over(self, mocker, capsys):
+        data = [
+            {'id': 1, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': ['a', 'b', 'c']}}},
+            {'id': 2, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': ['d', 'e']}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(warn_tags_over='2', dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert 'Warning: image version a:1 has 3 tags' in captured.out
+        assert 'a:2 has' not in captured.out
+
+    @pytest.mark.asyncio
+    async def test_cleanup_cosign_orphans(self, mocker, capsys):
+        old_digest, new_digest, gone_digest = 'a' * 64, 'b' * 64, 'c' * 64
+        now = datetime.now(timezone.utc).strftime('%Y-%m-%dT%H:%M:%SZ')
+
+        def version(id_, tags, created_at='2021-05-26T14:03:03Z', digest=None):
+            return {
+                'id': id_,
+                'name': f'sha256:{digest or id_}',
+                'created_at': created_at,
+                'metadata': {'container': {'tags': tags}},
+            }
+
+        data = [
+            version(1, [f'sha256-{old_digest}.sig'], now),
+            version(2, [f'sha256-{new_digest}.sig'], now),
+            version(3, [f'sha256-{gone_digest}.att'], now),
+            version(4, [f'sha256-{old_digest}.sbom', 'latest'], now),
+            version(5, ['v2'], now, new_digest),
+            version(6, ['v1'], digest=old_digest),
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(cut_off='a day ago UTC', dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        assert [line.split()[3] for line in capsys.readouterr().out.splitlines()] == ['a:6']
+
+        inputs = _create_inputs_model(cut_off='a day ago UTC', dry_run='true', cleanup_cosign_orphans='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        assert [line.split()[3] for line in capsys.readouterr().out.splitlines()] == ['a:1', 'a:3', 'a:6']
+
+        inputs = _create_inputs_model(
+            cut_off='a day ago UTC', dry_run='true', cleanup_cosign_orphans='true', skip_tags='*.sig'
+        )
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        assert [line.split()[3] for line in capsys.readouterr().out.splitlines()] == ['a:3', 'a:6']
+
+    @pytest.mark.asyncio
+    @pytest.mark.parametrize('priority, order', [('untagged-first', [2, 4, 1, 3]), ('tagged-first', [1, 3, 2, 4])])
+    async def test_priority(self, mocker, priority, order):
+        data = [
+            {'id': i, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': tags}}}
+            for i, tags in [(1, ['a']), (2, []), (3, ['b']), (4, [])]
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        mocked_delete = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+        inputs = _create_inputs_model(priority=priority)
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        assert [call.kwargs['version_id'] for call in mocked_delete.call_args_list] == order
+
+    @pytest.mark.asyncio
+    async def test_untagged_older_than_tagged(self, mocker, capsys):
+        data = [
+            {'id': 1, 'created_at': '2021-05-28T14:03:03Z', 'metadata': {'container': {'tags': []}}},
+            {'id': 2, 'created_at': '2021-05-27T14:03:03Z', 'metadata': {'container': {'tags': ['v2']}}},
+            {'id': 3, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': ['v1']}}},
+            {'id': 4, 'created_at': '2021-05-25T14:03:03Z', 'metadata': {'container': {'tags': []}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(untagged_older_than_tagged='true', dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        assert [line.split()[3] for line in capsys.readouterr().out.splitlines()] == ['a:2', 'a:3', 'a:4']
+
+        inputs = _create_inputs_model(dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        assert [line.split()[3] for line in capsys.readouterr().out.splitlines()] == ['a:1', 'a:2', 'a:3', 'a:4']
+
+    @pytest.mark.asyncio
+    async def test_untagged_older_than_tagged_logs_missing_timestamps_once(self, mocker, capsys):
+        data = [
+            {'id': 1, 'created_at': '2021-05-27T14:03:03Z', 'metadata': {'container': {'tags': ['v1']}}},
+            {'id': 2, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': []}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(untagged_older_than_tagged='true', timestamp_to_use='updated_at', dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert captured.out.count('Image version 1 has no updated_at timestamp. Using created_at instead.') == 1
+        assert 'Would delete image a:2' in captured.out
+
+    @pytest.mark.asyncio
+    async def test_selection_does_not_take_a_deletion_slot(self, mocker, capsys):
+        mocker.patch.object(
+            main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, self.valid_data)
+        )
+        # Another image's deletions hold the only slot, which shouldn't stop us from selecting versions
+        semaphore = Semaphore(1)
+        await semaphore.acquire()
+        inputs = _create_inputs_model(dry_run='true', parallel_deletes='1')
+        await asyncio.wait_for(get_and_delete_old_versions(ImageName('a', 'a'), inputs, mock_http_client, semaphore), 1)
+        main.print_dry_run_output()
+        assert 'Would delete image a:1234567' in capsys.readouterr().out
+
+    @pytest.mark.asyncio
+    async def test_get_package_versions_is_bounded(self, mocker):
+        in_flight, most_in_flight = 0, 0
+
+        async def get_package_version(*, version_id, **kwargs):
+            nonlocal in_flight, most_in_flight
+            in_flight += 1
+            most_in_flight = max(most_in_flight, in_flight)
+            await asyncio.sleep(0.001)
+            in_flight -= 1
+            return {'id': version_id}
+
+        mocker.patch.object(main.GithubAPI, 'get_package_version', get_package_version)
+        inputs = _create_inputs_model(concurrency_per_package='3')
+        versions = await main.get_package_versions(
+            image_name=ImageName('a', 'a'), version_ids=list(range(20)), inputs=inputs, http_client=mock_http_client
+        )
+        assert versions == [{'id': i} for i in range(20)]
+        assert most_in_flight == 3
+
+    @pytest.mark.asyncio
+    async def test_cleanup_cosign_orphans_verify_plan(self, mocker, capsys):
+        digest = 'a' * 64
+        now = datetime.now(timezone.utc).strftime('%Y-%m-%dT%H:%M:%SZ')
+        image = {
+            'id': 1,
+            'name': f'sha256:{digest}',
+            'created_at': '2021-05-26T14:03:03Z',
+            'metadata': {'container': {'tags': ['v1']}},
+        }
+        signature = {
+            'id': 2,
+            'name': 'sha256:' + 'b' * 64,
+            'created_at': now,
+            'metadata': {'container': {'tags': [f'sha256-{digest}.sig']}},
+        }
+        mocker.patch.object(
+            main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, [signature, image])
+        )
+        # The image was retagged since we listed it, so verify-plan drops it, and its signature has to stay too
+        current = {1: image | {'metadata': {'container': {'tags': ['v1', 'latest']}}}, 2: signature}
+        mocker.patch.object(
+            main.GithubAPI, 'get_package_version', AsyncMock(side_effect=lambda **kwargs: current[kwargs['version_id']])
+        )
+        inputs = _create_inputs_model(
+            cut_off='a day ago UTC', cleanup_cosign_orphans='true', verify_plan='true', dry_run='true'
+        )
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert 'Plan drift for a:1: tags changed from [v1] to [v1, latest]' in captured.out
+        assert 'Would delete' not in captured.out
+
+    @pytest.mark.asyncio
+    async def test_cleanup_cosign_orphans_keep_at_least_one(self, mocker, capsys):
+        digest = 'a' * 64
+        now = datetime.now(timezone.utc).strftime('%Y-%m-%dT%H:%M:%SZ')
+        signature = {'id': 2, 'name': 'sha256:' + 'b' * 64, 'created_at': now}
+        image = {'id': 1, 'name': f'sha256:{digest}', 'created_at': '2021-05-26T14:03:03Z'}
+        # The image is past the cut-off and its signature isn't, so only cleanup-cosign-orphans would empty the package
+        data = [
+            signature | {'metadata': {'container': {'tags': [f'sha256-{digest}.sig']}}},
+            image | {'metadata': {'container': {'tags': []}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(
+            cut_off='a day ago UTC', cleanup_cosign_orphans='true', keep_at_least_one='true', dry_run='true'
+        )
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert 'Keeping image version 1, so a has a version left' in captured.out
+        assert 'Would delete' not in captured.out
+
+    @pytest.mark.asyncio
+    async def test_verify_plan(self, mocker, capsys):
+        data = [
+            {'id': 1, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': ['a']}}},
+            {'id': 2, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': ['b']}}},
+            {'id': 3, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {'container': {'tags': ['c']}}},
+        ]
+        current = {1: data[0], 2: None, 3: data[2] | {'metadata': {'container': {'tags': ['c', 'latest']}}}}
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        mocker.patch.object(
+            main.GithubAPI, 'get_package_version', AsyncMock(side_effect=lambda **kwargs: current[kwargs['version_id']])
+        )
+        inputs = _create_inputs_model(verify_plan='true', dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert captured.out == (
+            'Plan drift for a:2: the version no longer exists\n'
+            'Plan drift for a:3: tags changed from [c] to [c, latest]\n'
+            'Would delete image a:1 (None, tags: a)\n'
+        )
+
     @pytest.mark.asyncio
     async def test_not_beyond_cutoff(self, mocker, capsys):
         response_data = [
@@ -286,6 +827,23 @@ class TestGetAndDeleteOldVersions:
         captured = capsys.readouterr()
         assert captured.out == 'No more versions to delete for a\n'
 
+    @pytest.mark.asyncio
+    async def test_skip_tags_multiple_tags(self, mocker, capsys):
+        # Like in v1, one skipped tag protects the whole version, even when filter-tags matches its other tags
+        data = [
+            deepcopy(self.valid_data[0]) | {'id': 1, 'metadata': {'container': {'tags': ['sha-1', 'latest']}}},
+            deepcopy(self.valid_data[0]) | {'id': 2, 'metadata': {'container': {'tags': ['sha-2', 'v1.0.0']}}},
+            deepcopy(self.valid_data[0]) | {'id': 3, 'metadata': {'container': {'tags': ['sha-3', 'pr-1']}}},
+        ]
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(filter_tags='sha-*', skip_tags='latest, v*', dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert 'a:1' not in captured.out
+        assert 'a:2' not in captured.out
+        assert 'Would delete image a:3' in captured.out
+
     @pytest.mark.asyncio
     async def test_untagged_only(self, mocker, capsys):
         data = deepcopy(self.valid_data)
@@ -306,11 +864,201 @@ class TestGetAndDeleteOldVersions:
         captured = capsys.readouterr()
         assert captured.out == 'Deleted old image: a:1234567\n'
 
+    @pytest.mark.asyncio
+    async def test_missing_updated_at_fallback(self, mocker, capsys):
+        data = deepcopy(self.valid_data)
+        del data[0]['updated_at']
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(timestamp_to_use='updated_at')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert captured.out == (
+            'Image version 1234567 has no updated_at timestamp. Using created_at instead.\n'
+            'Deleted old image: a:1234567\n'
+        )
+
+    @pytest.mark.asyncio
+    async def test_missing_updated_at_skip(self, mocker, capsys):
+        data = deepcopy(self.valid_data)
+        data[0]['updated_at'] = None
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        inputs = _create_inputs_model(timestamp_to_use='updated_at', on_missing_updated_at='skip')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        captured = capsys.readouterr()
+        assert (
+            captured.out
+            == 'Skipping image version 1234567. No updated_at timestamp.\nNo more versions to delete for a\n'
+        )
+
+    @pytest.mark.asyncio
+    async def test_missing_updated_at_error(self, mocker):
+        data = deepcopy(self.valid_data)
+        del data[0]['updated_at']
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        mocked_delete_package = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+        inputs = _create_inputs_model(timestamp_to_use='updated_at', on_missing_updated_at='error')
+        with pytest.raises(ValueError, match='Image version 1234567 has no updated_at timestamp'):
+            await get_and_delete_old_versions(
+                image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client
+            )
+        mocked_delete_package.assert_not_called()
+
+    @pytest.mark.asyncio
+    async def test_dry_run(self, mocker, capsys):
+        data = deepcopy(self.valid_data)
+        data[0]['metadata'] = {'container': {'tags': ['abc', 'bcd']}}
+        data.append(deepcopy(self.valid_data[0]) | {'id': 7654321, 'name': 'sha256:abc'})
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, data))
+        mocked_delete_package = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+        inputs = _create_inputs_model(dry_run='true')
+        await get_and_delete_old_versions(image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client)
+        main.print_dry_run_output()
+        captured = capsys.readouterr()
+        assert captured.out == (
+            f'Would delete image a:1234567 ({data[0]["name"]}, tags: abc, bcd)\n'
+            'Would delete image a:7654321 (sha256:abc, untagged)\n'
+        )
+        mocked_delete_package.assert_not_called()
+
+    @pytest.mark.asyncio
+    async def test_report_only_changes(self, mocker, capsys):
+        mocker.patch.object(main.GithubAPI, 'list_package_versions', partial(self._mock_list_package_versions, []))
+        inputs = _create_inputs_model(report_only_changes='true')
+        changed = await get_and_delete_old_versions(
+            image_name=ImageName('a', 'a'), inputs=inputs, http_client=mock_http_client
+        )
+        captured = capsys.readouterr()
+        assert changed is False
+        assert captured.out == ''
+
+
+class TestSelectionRules:
+    old = datetime(2021, 5, 26, tzinfo=timezone.utc)
+    new = datetime.now(timezone.utc)
+    tagged = {'id': 1, 'metadata': {'container': {'tags': ['v1.0.0', 'latest']}}}
+    untagged = {'id': 2, 'metadata': {'container': {'tags': []}}}
+
+    def test_cut_off_rule(self):
+        inputs = _create_inputs_model()
+        assert cut_off_rule(self.tagged, self.old, inputs) == Decision.DELETE
+        assert cut_off_rule(self.tagged, self.new, inputs) == Decision.NEUTRAL
+
+    def test_cut_off_rule_untagged_cut_off(self):
+        inputs = _create_inputs_model(cut_off='P1Y', untagged_cut_off='P1D')
+        two_days_ago = datetime.now(timezone.utc) - timedelta(days=2)
+        assert cut_off_rule(self.tagged, two_days_ago, inputs) == Decision.NEUTRAL
+        assert cut_off_rule(self.untagged, two_days_ago, inputs) == Decision.DELETE
+
+    def test_cut_off_rule_tag_cut_offs(self):
+        inputs = _create_inputs_model(cut_off='P365D', tag_cut_offs='nightly-*:P7D; rc-*:P30D')
+        ten_days_ago = datetime.now(timezone.utc) - timedelta(days=10)
+        nightly = {'id': 3, 'metadata': {'container': {'tags': ['nightly-42']}}}
+        rc = {'id': 4, 'metadata': {'container': {'tags': ['rc-1']}}}
+        both = {'id': 5, 'metadata': {'container': {'tags': ['nightly-42', 'rc-1']}}}
+        assert cut_off_rule(nightly, ten_days_ago, inputs) == Decision.DELETE
+        assert cut_off_rule(rc, ten_days_ago, inputs) == Decision.NEUTRAL
+        assert cut_off_rule(both, ten_days_ago, inputs) == Decision.NEUTRAL
+        assert cut_off_rule(both, ten_days_ago - timedelta(days=30), inputs) == Decision.DELETE
+        assert cut_off_rule(self.tagged, ten_days_ago - timedelta(days=30), inputs) == Decision.NEUTRAL
+        with pytest.raises(ValueError, match='Tag cut-offs must be formatted as <pattern>:<cut-off>'):
+            _create_inputs_model(tag_cut_offs='nightly-*')
+
+    def test_tag_cut_offs_with_commas(self):
+        inputs = _create_inputs_model(tag_cut_offs='nightly-*:May 26, 2021 UTC\nrc-*:P30D')
+        assert [pattern for pattern, _ in inputs.tag_cut_offs] == ['nightly-*', 'rc-*']
+        assert inputs.tag_cut_offs[0][1] == datetime(2021, 5, 26, tzinfo=timezone.utc)
+
+    def test_untagged_only_rule(self):
+        assert untagged_only_rule(self.tagged, self.old, _create_inputs_model()) == Decision.NEUTRAL
+        inputs = _create_inputs_model(untagged_only='true')
+        assert untagged_only_rule(self.tagged, self.old, inputs) == Decision.KEEP
+        assert untagged_only_rule(self.untagged, self.old, inputs) == Decision.NEUTRAL
+
+    def test_filter_include_untagged_rule(self):
+        assert filter_include_untagged_rule(self.untagged, self.old, _create_inputs_model()) == Decision.NEUTRAL
+        inputs = _create_inputs_model(filter_include_untagged='false')
+        assert filter_include_untagged_rule(self.untagged, self.old, inputs) == Decision.KEEP
+        assert filter_include_untagged_rule(self.tagged, self.old, inputs) == Decision.NEUTRAL
+
+    def test_filter_tags_rule(self):
+        assert filter_tags_rule(self.tagged, self.old, _create_inputs_model()) == Decision.NEUTRAL
+        inputs = _create_inputs_model(filter_tags='v1.*')
+        assert filter_tags_rule(self.tagged, self.old, inputs) == Decision.NEUTRAL
+        assert filter_tags_rule(self.untagged, self.old, inputs) == Decision.KEEP
+        inputs = _create_inputs_model(filter_tags='sha-*')
+        assert filter_tags_rule(self.tagged, self.old, inputs) == Decision.KEEP
+
+    def test_filter_version_names_rule(self):
+        version = self.tagged | {'name': 'sha256:3c6891187412bd31fa04c63b4f06c47417eb599b1b659462632285531aa99c19'}
+        assert filter_version_names_rule(version, self.old, _create_inputs_model()) == Decision.NEUTRAL
+        inputs = _create_inputs_model(filter_version_names='sha256:3c68*, sha256:ab*')
+        assert filter_version_names_rule(version, self.old, inputs) == Decision.NEUTRAL
+        inputs = _create_inputs_model(filter_version_names='sha256:ab*')
+        assert filter_version_names_rule(version, self.old, inputs) == Decision.KEEP
+        inputs = _create_inputs_model(filter_version_names='sha256:3c68*', filter_tags='sha-*')
+        assert resolve_decisions([rule(version, self.old, inputs) for rule in main.SELECTION_RULES]) is False
+
+    def test_match_short_digest(self):
+        version = self.untagged | {'name': 'sha256:3c6891187412bd31fa04c63b4f06c47417eb599b1b659462632285531aa99c19'}
+        inputs = _create_inputs_model(filter_tags='3c6891*')
+        assert filter_tags_rule(version, self.old, inputs) == Decision.KEEP
+        inputs = _create_inputs_model(filter_tags='3c6891*', match_short_digest='true')
+        assert filter_tags_rule(version, self.old, inputs) == Decision.NEUTRAL
+        inputs = _create_inputs_model(filter_tags='3c6891187412bd*', match_short_digest='true')
+        assert filter_tags_rule(version, self.old, inputs) == Decision.KEEP
+        inputs = _create_inputs_model(skip_tags='3c6891187412', match_short_digest='true')
+        assert skip_tags_rule(version, self.old, inputs) == Decision.PROTECT
+
+    def test_skip_tags_rule(self):
+        assert skip_tags_rule(self.tagged, self.old, _create_inputs_model()) == Decision.NEUTRAL
+        inputs = _create_inputs_model(skip_tags='lat*')
+        assert skip_tags_rule(self.tagged, self.old, inputs) == Decision.PROTECT
+        assert skip_tags_rule(self.untagged, self.old, inputs) == Decision.NEUTRAL
+
+    def test_release_digests_rule(self, mocker):
+        mocker.patch.object(main, 'protected_digests', {'sha256:abc'})
+        inputs = _create_inputs_model()
+        assert release_digests_rule(self.tagged | {'name': 'sha256:abc'}, self.old, inputs) == Decision.PROTECT
+        assert release_digests_rule(self.tagged | {'name': 'sha256:def'}, self.old, inputs) == Decision.NEUTRAL
+
+    def test_active_branches_rule(self, mocker):
+        mocker.patch.object(main, 'protected_branch_tags', {'branch-main'})
+        inputs = _create_inputs_model()
+        branch_main = {'id': 3, 'metadata': {'container': {'tags': ['branch-main']}}}
+        branch_gone = {'id': 4, 'metadata': {'container': {'tags': ['branch-feature-x']}}}
+        assert active_branches_rule(branch_main, self.old, inputs) == Decision.PROTECT
+        assert active_branches_rule(branch_gone, self.old, inputs) == Decision.NEUTRAL
+        assert active_branches_rule(self.untagged, self.old, inputs) == Decision.NEUTRAL
+
+    def test_created_today_rule(self):
+        now = datetime.now(timezone.utc)
+        created_today = {'id': 5, 'created_at': now.isoformat()}
+        created_yesterday = {'id': 6, 'created_at': (now - timedelta(days=1)).isoformat()}
+        assert created_today_rule(created_today, self.old, _create_inputs_model()) == Decision.NEUTRAL
+        inputs = _create_inputs_model(exclude_created_today='true')
+        assert created_today_rule(created_today, self.old, inputs) == Decision.PROTECT
+        assert created_today_rule(created_yesterday, self.old, inputs) == Decision.NEUTRAL
+
+    @pytest.mark.parametrize(
+        'decisions,delete',
+        [
+            ([], False),
+            ([Decision.NEUTRAL, Decision.NEUTRAL], False),
+            ([Decision.DELETE, Decision.NEUTRAL], True),
+            ([Decision.DELETE, Decision.KEEP], False),
+            ([Decision.KEEP, Decision.PROTECT], False),
+            ([Decision.DELETE, Decision.PROTECT, Decision.NEUTRAL], False),
+        ],
+    )
+    def test_resolve_decisions(self, decisions, delete):
+        assert resolve_decisions(decisions) is delete
+
 
 def test_inputs_bad_account_type():
     # Account type
     _create_inputs_model(account_type='personal')
     _create_inputs_model(account_type='org')
+    _create_inputs_model(account_type='auto', org_name='')
     with pytest.raises(ValidationError, match='is not a valid enumeration member'):
         _create_inputs_model(account_type='')
 
@@ -333,6 +1081,26 @@ def test_inputs_bad_account_type():
     with pytest.raises(ValueError, match="Unable to parse 'lolol'"):
         _create_inputs_model(cut_off='lolol')
 
+    # Untagged cut-off
+    inputs = _create_inputs_model(untagged_cut_off='')
+    assert inputs.untagged_cut_off == inputs.cut_off
+    assert _create_inputs_model(untagged_cut_off='P1D').untagged_cut_off < inputs.cut_off
+    with pytest.raises(ValueError, match='Timezone is required for the cut-off'):
+        _create_inputs_model(untagged_cut_off='12/12/12')
+
+    # Cut-off as an ISO 8601 duration
+    for duration, expected in [
+        ('P90D', timedelta(days=90)),
+        ('P1M', timedelta(days=30)),
+        ('PT48H', timedelta(hours=48)),
+        ('p1y2w', timedelta(days=379)),
+    ]:
+        cut_off = _create_inputs_model(cut_off=duration).cut_off
+        assert cut_off.tzinfo is not None
+        assert abs(datetime.now(timezone.utc) - expected - cut_off) < timedelta(seconds=5)
+    with pytest.raises(ValueError, match="Unable to parse 'PT'"):
+        _create_inputs_model(cut_off='PT')
+
     # Untagged only
     for i in ['true', 'True', '1']:
         assert _create_inputs_model(untagged_only=i).untagged_only is True
@@ -354,12 +1122,86 @@ def test_inputs_bad_account_type():
     assert _create_inputs_model(filter_tags='sha-*,latest').filter_tags == ['sha-*', 'latest']
     assert _create_inputs_model(filter_tags='sha-* , latest').filter_tags == ['sha-*', 'latest']
 
+    # All packages
+    assert _create_inputs_model(image_names='', all_packages='true').all_packages is True
+    with pytest.raises(ValueError, match='image-names is required, unless all-packages is enabled'):
+        _create_inputs_model(image_names='')
+    with pytest.raises(ValueError, match='image-names cannot be combined with all-packages'):
+        _create_inputs_model(image_names='a', all_packages='true')
+
+    # Dry run nonempty exit code
+    assert _create_inputs_model(dry_run_nonempty_exit_code='3').dry_run_nonempty_exit_code == 3
+    with pytest.raises(ValueError, match='ensure this value is less than or equal to 255'):
+        _create_inputs_model(dry_run_nonempty_exit_code='256')
+
+    # Concurrency per package
+    assert _create_inputs_model(concurrency_per_package='3').concurrency_per_package == 3
+    with pytest.raises(ValueError, match='ensure this value is greater than or equal to 1'):
+        _create_inputs_model(concurrency_per_package='0')
+
     # Filter include untagged
     for i in ['true', 'True', '1', True]:
         assert _create_inputs_model(filter_include_untagged=i).filter_include_untagged is True
     for j in ['False', 'false', '0', False]:
         assert _create_inputs_model(filter_include_untagged=j).filter_include_untagged is False
 
+    # On missing updated at
+    for policy in ['fallback', 'skip', 'error']:
+        assert _create_inputs_model(on_missing_updated_at=policy).on_missing_updated_at == policy
+    with pytest.raises(ValueError, match='value is not a valid enumeration member'):
+        _create_inputs_model(on_missing_updated_at='ignore')
+
+
+@pytest.mark.parametrize(
+    'image_names,expected',
+    [
+        ('service-{api,worker,web}', ['service-api', 'service-worker', 'service-web']),
+        ('{a,b}-{1,2}', ['a-1', 'a-2', 'b-1', 'b-2']),
+        ('a{b,c{d,e}}, f', ['ab', 'acd', 'ace', 'f']),
+        ('a{b}', ['a{b}']),
+        ('a\\{b\\,c}', ['a{b,c}']),
+    ],
+)
+def test_image_names_brace_expansion(image_names, expected):
+    assert _create_inputs_model(image_names=image_names).image_names == expected
+
+
+def test_image_names_with_tags(capsys):
+    inputs = _create_inputs_model(image_names='webapp:latest, worker:latest', filter_tags='sha-*')
+    assert inputs.image_names == ['webapp', 'worker']
+    assert inputs.filter_tags == ['sha-*', 'latest']
+    assert capsys.readouterr().out == (
+        "Image names can't contain tags. Treating 'webapp:latest' as image name 'webapp', "
+        "and adding 'latest' to the filter-tags\n"
+        "Image names can't contain tags. Treating 'worker:latest' as image name 'worker', "
+        "and adding 'latest' to the filter-tags\n"
+    )
+
+
+@pytest.mark.parametrize('image_names', ['webapp:latest, worker', 'webapp:latest, worker:edge'])
+def test_image_names_with_different_tags(image_names):
+    with pytest.raises(ValueError, match='Image names with tags can only be combined with image names with the same'):
+        _create_inputs_model(image_names=image_names)
+
+
+def test_version_ids():
+    assert _create_inputs_model(image_names='a', version_ids='1, 2').version_ids == [1, 2]
+    assert _create_inputs_model(image_names='a,b').version_ids == []
+    with pytest.raises(ValueError, match='version-ids must be a comma-separated list of numeric version IDs'):
+        _create_inputs_model(image_names='a', version_ids='1,abc')
+    with pytest.raises(ValueError, match='version-ids requires image-names to be a single image name'):
+        _create_inputs_model(image_names='a,b', version_ids='1')
+    with pytest.raises(ValueError, match='version-ids requires image-names to be a single image name'):
+        _create_inputs_model(image_names='a*', version_ids='1')
+
+
+def test_filter_tags_env_interpolation(mocker):
+    mocker.patch.dict(main.environ, {'PR_NUMBER': '123'})
+    assert _create_inputs_model(filter_tags='pr-${PR_NUMBER},latest').filter_tags == ['pr-123', 'latest']
+    mocker.patch.dict(main.environ, clear=True)
+    with pytest.raises(ValueError, match='Environment variable PR_NUMBER is not set'):
+        _create_inputs_model(filter_tags='pr-${PR_NUMBER}')
+
 
 def test_parse_image_names():
     assert filter_image_names(
@@ -384,26 +1226,292 @@ def test_parse_image_names():
     }
 
 
+main_defaults = {
+    'account_type': 'org',
+    'org_name': 'test',
+    'image_names': 'a,b,c',
+    'timestamp_to_use': 'updated_at',
+    'cut_off': '2 hours ago UTC',
+    'untagged_only': 'false',
+    'skip_tags': '',
+    'keep_at_least': '0',
+    'filter_tags': '',
+    'filter_include_untagged': 'true',
+    'on_missing_updated_at': 'fallback',
+    'dry_run': 'false',
+    'concurrency_per_package': '1',
+    'report_only_changes': 'false',
+    'all_packages': 'false',
+    'keep_at_least_one': 'false',
+    'untagged_cut_off': '',
+    'dry_run_nonempty_exit_code': '0',
+    'max_pages': '0',
+    'strip_prefix': '',
+    'delete_duplicate_tags': 'false',
+    'protect_last_releases': '0',
+    'min_rate_limit': '0',
+    'explain_rate_limit': 'false',
+    'package_type': 'container',
+    'max_runtime': '',
+    'protect_active_branches': 'false',
+    'print_config': 'false',
+    'version_ids': '',
+    'parallel_deletes': '50',
+    'max_versions_scanned': '0',
+    'token_file': '',
+    'exclude_created_today': 'false',
+    'only_visibility': 'all',
+    'verify_plan': 'false',
+    'on_secondary_rate_limit': 'retry',
+    'package_name_regex': '',
+    'package_name_regex_exclude': '',
+    'timing': 'false',
+    'tag_cut_offs': '',
+    'confirm': '',
+    'packages_only': 'false',
+    'retry_failed_from': '',
+    'filter_version_names': '',
+    'on_empty_account': 'warn',
+    'verify_deletions': 'false',
+    'warn_tags_over': '0',
+    'cleanup_cosign_orphans': 'false',
+    'skip_if_updated_within': '',
+    'match_short_digest': 'false',
+    'halt_on_auth_errors': 'true',
+    'priority': 'untagged-first',
+    'pushed_manifest': '',
+    'untagged_older_than_tagged': 'false',
+    'circuit_breaker_threshold': '0',
+    'token': 'test',
+}
+
+
+@pytest.mark.asyncio
+async def test_list_release_digests():
+    digest = 'sha256:' + 'a' * 64
+    http_client = AsyncMock()
+    http_client.get.return_value = Mock()
+    http_client.get.return_value.json.return_value = [
+        {'body': f'## Images\n\nghcr.io/snok/a@{digest}\n'},
+        {'body': None},
+    ]
+    assert await list_release_digests(repository='snok/a', count=2, http_client=http_client) == {digest}
+    assert http_client.get.call_args.args[0] == 'https://api.github.com/repos/snok/a/releases?per_page=2'
+
+
+@pytest.mark.asyncio
+async def test_list_branch_tags():
+    http_client = AsyncMock()
+    http_client.get.return_value = Mock()
+    http_client.get.return_value.json.return_value = [{'name': 'main'}, {'name': 'feature/x'}]
+    assert await list_branch_tags(repository='snok/a', http_client=http_client) == {'branch-main', 'branch-feature-x'}
+    assert http_client.get.call_args.args[0] == 'https://api.github.com/repos/snok/a/branches?per_page=100&page=1'
+
+
+@pytest.mark.asyncio
+async def test_protect_active_branches(mocker):
+    mocker.patch.object(main, 'protected_branch_tags', set())
+    mocker.patch.dict(main.environ, {'GITHUB_REPOSITORY': 'snok/a'})
+    mocker.patch.object(main, 'list_branch_tags', AsyncMock(return_value={'branch-main'}))
+    mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'protect_active_branches': 'true'}))
+    assert main.protected_branch_tags == {'branch-main'}
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('status_code,account_type', [(200, AccountType.ORG), (404, AccountType.PERSONAL)])
+async def test_detect_account_type(status_code, account_type):
+    http_client = AsyncMock()
+    http_client.get.return_value = Mock()
+    http_client.get.return_value.status_code = status_code
+    assert await detect_account_type(owner='snok', http_client=http_client) == account_type
+    assert http_client.get.call_args.args[0] == 'https://api.github.com/orgs/snok'
+
+
+@pytest.mark.asyncio
+async def test_get_authenticated_user():
+    http_client = AsyncMock()
+    http_client.get.return_value = Mock()
+    http_client.get.return_value.json.return_value = {'login': 'snok'}
+    assert await get_authenticated_user(http_client=http_client) == 'snok'
+    assert http_client.get.call_args.args[0] == 'https://api.github.com/user'
+
+
+@pytest.mark.asyncio
+async def test_account_type_auto(mocker, capsys):
+    mocker.patch.dict(main.environ, {'GITHUB_REPOSITORY_OWNER': 'snok'})
+    mocker.patch.object(main, 'detect_account_type', AsyncMock(return_value=AccountType.ORG))
+    mocked_list_packages = mocker.patch.object(main.GithubAPI, 'list_packages', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'account_type': 'auto', 'org_name': ''}))
+    assert main.detect_account_type.call_args.kwargs['owner'] == 'snok'
+    assert mocked_list_packages.call_args.kwargs['account_type'] == AccountType.ORG
+    assert mocked_list_packages.call_args.kwargs['org_name'] == 'snok'
+    assert 'Detected account type org for snok\n' in capsys.readouterr().out
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('login', ['snok', 'Snok'])
+async def test_account_type_auto_personal(mocker, login):
+    mocker.patch.dict(main.environ, {'GITHUB_REPOSITORY_OWNER': 'snok'})
+    mocker.patch.object(main, 'detect_account_type', AsyncMock(return_value=AccountType.PERSONAL))
+    mocker.patch.object(main, 'get_authenticated_user', AsyncMock(return_value=login))
+    mocked_list_packages = mocker.patch.object(main.GithubAPI, 'list_packages', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'account_type': 'auto', 'org_name': ''}))
+    assert mocked_list_packages.call_args.kwargs['account_type'] == AccountType.PERSONAL
+
+
+@pytest.mark.asyncio
+async def test_account_type_auto_personal_other_token_owner(mocker):
+    mocker.patch.dict(main.environ, {'GITHUB_REPOSITORY_OWNER': 'snok'})
+    mocker.patch.object(main, 'detect_account_type', AsyncMock(return_value=AccountType.PERSONAL))
+    mocker.patch.object(main, 'get_authenticated_user', AsyncMock(return_value='someone-else'))
+    mocked_list_packages = mocker.patch.object(main.GithubAPI, 'list_packages', AsyncMock(return_value=[]))
+    with pytest.raises(ValueError, match='snok is a personal account, but the token belongs to someone-else'):
+        await main_(**(main_defaults | {'account_type': 'auto', 'org_name': ''}))
+    assert not mocked_list_packages.called
+
+
+@pytest.mark.asyncio
+async def test_account_type_explicit_does_not_probe(mocker):
+    mocker.patch.object(main, 'detect_account_type', AsyncMock())
+    mocker.patch.object(main.GithubAPI, 'list_packages', AsyncMock(return_value=[]))
+    await main_(**main_defaults)
+    assert not main.detect_account_type.called
+
+
+@pytest.mark.asyncio
+async def test_protect_last_releases(mocker, capsys):
+    digest = 'sha256:' + 'a' * 64
+    mocker.patch.object(main, 'protected_digests', set())
+    mocker.patch.dict(main.environ, {'GITHUB_REPOSITORY': 'snok/a'})
+    mocker.patch.object(main, 'list_release_digests', AsyncMock(return_value={digest}))
+    mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'protect_last_releases': '3'}))
+    assert main.list_release_digests.call_args.kwargs['repository'] == 'snok/a'
+    assert main.protected_digests == {digest}
+    assert 'Protecting 1 image digest(s) referenced by the latest releases\n' in capsys.readouterr().out
+
+
+@pytest.mark.asyncio
+async def test_list_packages_with_retries(mocker, capsys):
+    packages = [PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now())]
+    mocked_list_packages = mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(side_effect=[ConnectError('DNS hiccup'), ConnectError('TLS hiccup'), packages]),
+    )
+    result = await list_packages_with_retries(inputs=_create_inputs_model(), http_client=AsyncMock(), backoff=0)
+    assert result == packages
+    assert mocked_list_packages.await_count == 3
+    assert capsys.readouterr().out == (
+        'Unable to reach the Github API: `DNS hiccup`. Retrying in 0 seconds\n'
+        'Unable to reach the Github API: `TLS hiccup`. Retrying in 0 seconds\n'
+    )
+
+
+@pytest.mark.asyncio
+async def test_list_packages_with_retries_gives_up(mocker):
+    mocker.patch.object(main.GithubAPI, 'list_packages', AsyncMock(side_effect=ConnectError('DNS hiccup')))
+    with pytest.raises(ConnectionError, match='Unable to reach the Github API after 3 attempts: `DNS hiccup`'):
+        await list_packages_with_retries(inputs=_create_inputs_model(), http_client=AsyncMock(), backoff=0)
+
+
+@pytest.mark.asyncio
+async def test_account_type_auto_probe_is_retried(mocker, capsys):
+    mocker.patch.object(main.asyncio, 'sleep', AsyncMock())
+    mocker.patch.object(
+        main, 'detect_account_type', AsyncMock(side_effect=[ConnectError('DNS hiccup'), AccountType.PERSONAL])
+    )
+    mocker.patch.object(main, 'get_authenticated_user', AsyncMock(return_value='snok'))
+    mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'account_type': 'auto', 'org_name': 'snok'}))
+    assert main.detect_account_type.await_count == 2
+    assert 'Unable to reach the Github API: `DNS hiccup`. Retrying in 1 seconds\n' in capsys.readouterr().out
+
+
+def test_filter_visibility(capsys):
+    now = datetime.now()
+    packages = [
+        PackageResponse(id=i, name=visibility or 'missing', created_at=now, updated_at=now, visibility=visibility)
+        for i, visibility in enumerate(['public', 'private', 'internal', '', 'secret'])
+    ]
+    assert filter_visibility(packages, VisibilityFilter.ALL) == packages
+    assert [p.name for p in filter_visibility(packages, VisibilityFilter.PUBLIC)] == ['public']
+    capsys.readouterr()
+    assert [p.name for p in filter_visibility(packages, VisibilityFilter.PRIVATE)] == ['private', 'internal']
+    assert capsys.readouterr().out == (
+        'Warning: skipping package missing, since its visibility is unknown\n'
+        'Warning: skipping package secret, since its visibility is unknown\n'
+    )
+
+
+def test_filter_package_name_regex():
+    now = datetime.now()
+    packages = [
+        PackageResponse(id=i, name=name, created_at=now, updated_at=now)
+        for i, name in enumerate(['api', 'api-test', 'keep-api-test', 'worker'])
+    ]
+    inputs = _create_inputs_model(package_name_regex='^(keep-|api)', package_name_regex_exclude='^(?!keep-).*-test$')
+    filtered = filter_package_name_regex(packages, inputs.package_name_regex, inputs.package_name_regex_exclude)
+    assert [p.name for p in filtered] == ['api', 'keep-api-test']
+    assert filter_package_name_regex(packages, None, None) == packages
+    with pytest.raises(ValueError, match="Unable to compile '\\('"):
+        _create_inputs_model(package_name_regex='(')
+
+
+def test_parse_image_names_strip_prefix():
+    assert filter_image_names(
+        all_packages=[
+            PackageResponse(id=1, name='service-foo', created_at=datetime.now(), updated_at=datetime.now()),
+            PackageResponse(id=2, name='service-bar', created_at=datetime.now(), updated_at=datetime.now()),
+            PackageResponse(id=3, name='qux', created_at=datetime.now(), updated_at=datetime.now()),
+            PackageResponse(id=4, name='baz', created_at=datetime.now(), updated_at=datetime.now()),
+        ],
+        image_names=['foo', 'b*'],
+        strip_prefix='service-',
+    ) == {
+        ImageName('foo', 'service-foo'),
+        ImageName('bar', 'service-bar'),
+        ImageName('baz', 'baz'),
+    }
+
+
+def test_parse_image_names_strip_prefix_collision():
+    all_packages = [
+        PackageResponse(id=1, name='service-foo', created_at=datetime.now(), updated_at=datetime.now()),
+        PackageResponse(id=2, name='foo', created_at=datetime.now(), updated_at=datetime.now()),
+    ]
+    with pytest.raises(ValueError, match='The packages foo and service-foo are both named foo'):
+        filter_image_names(all_packages=all_packages, image_names=['foo'], strip_prefix='service-')
+    assert filter_image_names(all_packages=all_packages, image_names=['*']) == {
+        ImageName('service-foo', 'service-foo'),
+        ImageName('foo', 'foo'),
+    }
+
+
+@pytest.mark.asyncio
+async def test_strip_prefix_uses_full_name_in_urls(capsys):
+    http_client = AsyncMock()
+    http_client.get.return_value = mock_response
+    http_client.delete.return_value = mock_response
+    image_name = ImageName('foo', 'service-foo')
+
+    await list_package_versions(image_name=image_name, http_client=http_client)
+    await delete_package_versions(
+        image_name=image_name, http_client=http_client, version_id=123, semaphore=Semaphore(1)
+    )
+
+    assert http_client.get.call_args.args[0].startswith(f'{main.BASE_URL}/user/packages/container/service-foo/versions')
+    assert http_client.delete.call_args.args[0] == f'{main.BASE_URL}/user/packages/container/service-foo/versions/123'
+    assert capsys.readouterr().out == 'Deleted old image: foo:123\n'
+
+
 @pytest.mark.asyncio
 async def test_main(mocker):
     mocker.patch.object(AsyncClient, 'get', return_value=mock_response)
     mocker.patch.object(AsyncClient, 'delete', return_value=mock_response)
     mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock())
-    await main_(
-        **{
-            'account_type': 'org',
-            'org_name': 'test',
-            'image_names': 'a,b,c',
-            'timestamp_to_use': 'updated_at',
-            'cut_off': '2 hours ago UTC',
-            'untagged_only': 'false',
-            'skip_tags': '',
-            'keep_at_least': '0',
-            'filter_tags': '',
-            'filter_include_untagged': 'true',
-            'token': 'test',
-        }
-    )
+    await main_(**main_defaults)
 
 
 @pytest.mark.asyncio
@@ -444,21 +1552,7 @@ async def test_public_images_with_more_than_5000_downloads(mocker, capsys):
 
     mocker.patch.object(AsyncClient, 'get', return_value=mock_list_response)
     mocker.patch.object(AsyncClient, 'delete', return_value=mock_delete_response)
-    await main_(
-        **{
-            'account_type': 'org',
-            'org_name': 'test',
-            'image_names': 'a,b,c',
-            'timestamp_to_use': 'updated_at',
-            'cut_off': '2 hours ago UTC',
-            'untagged_only': 'false',
-            'skip_tags': '',
-            'keep_at_least': '0',
-            'filter_tags': '',
-            'filter_include_untagged': 'true',
-            'token': 'test',
-        }
-    )
+    await main_(**main_defaults)
     captured = capsys.readouterr()
 
     for m in [
@@ -506,21 +1600,7 @@ async def test_outputs_are_set(mocker, capsys):
     mocker.patch.object(AsyncClient, 'get', return_value=mock_list_response)
     mocker.patch.object(AsyncClient, 'delete', return_value=RotatingStatusCodeMock())
 
-    await main_(
-        **{
-            'account_type': 'org',
-            'org_name': 'test',
-            'image_names': 'a,b,c',
-            'timestamp_to_use': 'updated_at',
-            'cut_off': '2 hours ago UTC',
-            'untagged_only': 'false',
-            'skip_tags': '',
-            'keep_at_least': '0',
-            'filter_tags': '',
-            'filter_include_untagged': 'true',
-            'token': 'test',
-        }
-    )
+    await main_(**main_defaults)
     captured = capsys.readouterr()
     out = captured.out
     for i in [
@@ -529,3 +1609,597 @@ async def test_outputs_are_set(mocker, capsys):
         '::set-output name=failed::',
     ]:
         assert i in out
+
+
+@pytest.mark.asyncio
+async def test_report_only_changes_summary(mocker, capsys):
+    mocker.patch.object(AsyncClient, 'get', return_value=mock_response)
+    mocker.patch.object(
+        main, 'filter_image_names', return_value={ImageName('a', 'a'), ImageName('b', 'b'), ImageName('c', 'c')}
+    )
+    mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock(side_effect=[True, False, False]))
+    await main_(**(main_defaults | {'report_only_changes': 'true'}))
+    captured = capsys.readouterr()
+    assert 'No more versions to delete for 2 of 3 image(s)\n' in captured.out
+
+
+@pytest.mark.asyncio
+async def test_all_packages(mocker):
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now()),
+                PackageResponse(id=2, name='b', created_at=datetime.now(), updated_at=datetime.now()),
+            ]
+        ),
+    )
+    mocked_get_and_delete = mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock())
+    await main_(**(main_defaults | {'image_names': '', 'all_packages': 'true'}))
+    assert {call.args[0] for call in mocked_get_and_delete.call_args_list} == {ImageName('a', 'a'), ImageName('b', 'b')}
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('remaining,skipped', [(99, True), (100, False)])
+async def test_min_rate_limit(mocker, capsys, remaining, skipped):
+    mocker.patch.object(main, 'deleted', [])
+    mocker.patch.object(main, 'failed', [])
+    mocker.patch.object(main, 'get_rate_limit', AsyncMock(return_value={'remaining': remaining}))
+    mocked_list_packages = mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'min_rate_limit': '100'}))
+    captured = capsys.readouterr()
+    assert ('Skipping this run: only 99 requests left in the rate limit, need 100\n' in captured.out) is skipped
+    assert mocked_list_packages.called is not skipped
+    # The outputs are set either way, so later steps can read them
+    assert '::set-output name=deleted::\n' in captured.out
+    assert '::set-output name=failed::\n' in captured.out
+
+
+@pytest.mark.asyncio
+async def test_min_rate_limit_is_retried(mocker, capsys):
+    mocker.patch.object(main.asyncio, 'sleep', AsyncMock())
+    mocked_get_rate_limit = mocker.patch.object(
+        main, 'get_rate_limit', AsyncMock(side_effect=[ConnectError('TLS hiccup'), {'remaining': 100}])
+    )
+    mocked_list_packages = mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'min_rate_limit': '100'}))
+    assert mocked_get_rate_limit.await_count == 2
+    assert mocked_list_packages.called
+    assert 'Unable to reach the Github API: `TLS hiccup`. Retrying in 1 seconds\n' in capsys.readouterr().out
+
+
+@pytest.mark.asyncio
+async def test_explain_rate_limit(mocker, capsys):
+    mocker.patch.object(main, 'requests_sent', Counter())
+    mocker.patch.object(
+        main,
+        'get_rate_limit',
+        AsyncMock(
+            side_effect=[{'limit': 5000, 'remaining': 4995, 'reset': 0}, {'limit': 5000, 'remaining': 4990, 'reset': 0}]
+        ),
+    )
+    mock_list_response = Mock()
+    mock_list_response.json = lambda: [
+        {'id': 1, 'updated_at': '2021-05-26T14:03:03Z', 'name': 'a', 'created_at': '2021-05-26T14:03:03Z'}
+    ]
+    mocker.patch.object(AsyncClient, 'get', return_value=mock_list_response)
+    mocker.patch.object(AsyncClient, 'delete', return_value=mock_response)
+    await main_(**(main_defaults | {'image_names': 'a', 'explain_rate_limit': 'true'}))
+    captured = capsys.readouterr()
+    assert (
+        'Requests sent to the Github API:\n'
+        '\t- list packages: 1\n'
+        '\t- list versions: 1\n'
+        '\t- delete: 1\n'
+        '\t- other: 0\n'
+        '\t- total: 3\n'
+        '4995 of 5000 requests left at the start of the run\n'
+        '5 requests used from the rate limit\n'
+        '4990 of 5000 requests left, until the rate limit resets at 1970-01-01 00:00:00+00:00\n'
+    ) in captured.out
+
+
+@pytest.mark.asyncio
+async def test_max_runtime(mocker, capsys):
+    mocker.patch.object(main, 'deadline', None)
+    mocker.patch.object(main, 'skipped_by_deadline', [])
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(return_value=[PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now())]),
+    )
+    mocked_list_versions = mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'image_names': 'a', 'max_runtime': 'PT0S'}))
+    assert not mocked_list_versions.called
+    assert 'Skipping a, since the max-runtime was reached\n' in capsys.readouterr().out
+    with pytest.raises(ValueError, match='The max-runtime must be an ISO 8601 duration'):
+        _create_inputs_model(max_runtime='30 minutes')
+
+
+@pytest.mark.asyncio
+async def test_print_config(mocker, capsys):
+    mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    await main_(**(main_defaults | {'token': 'ghp_secret', 'print_config': 'true', 'tag_cut_offs': 'rc-*:P30D'}))
+    captured = capsys.readouterr()
+    assert 'ghp_secret' not in captured.out
+    assert '\t- token: ***\n' in captured.out
+    assert '\t- image-names: a, b, c\n' in captured.out
+    assert '\t- account-type: org\n' in captured.out
+    assert '\t- tag-cut-offs: rc-*:' in captured.out
+    for field in Inputs.__fields__:
+        assert f'\t- {field.replace("_", "-")}: ' in captured.out
+
+
+@pytest.mark.asyncio
+async def test_parallel_deletes(mocker):
+    in_flight = []
+    max_in_flight = []
+
+    async def delete(self, url):
+        in_flight.append(url)
+        max_in_flight.append(len(in_flight))
+        await asyncio.sleep(0.01)
+        in_flight.remove(url)
+        return mock_response
+
+    mocker.patch.object(AsyncClient, 'delete', delete)
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=i, name=name, created_at=datetime.now(), updated_at=datetime.now())
+                for i, name in enumerate(['a', 'b'])
+            ]
+        ),
+    )
+    versions = [{'id': i, 'created_at': '2021-05-26T14:03:03Z', 'updated_at': '2021-05-26T14:03:03Z'} for i in range(5)]
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock(return_value=versions))
+    mocker.patch.object(main, 'deleted', [])
+    await main_(**(main_defaults | {'image_names': 'a,b', 'parallel_deletes': '2'}))
+    assert len(main.deleted) == 10
+    assert max(max_in_flight) == 2
+
+
+@pytest.mark.asyncio
+async def test_max_versions_scanned(mocker, capsys):
+    mocker.patch.object(main, 'versions_scanned', 0)
+    mocker.patch.object(main, 'versions_scanned_lock', asyncio.Lock())
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=i, name=name, created_at=datetime.now(), updated_at=datetime.now())
+                for i, name in enumerate(['a', 'b'])
+            ]
+        ),
+    )
+    http_client = PaginatedHttpClient(total=1000)
+
+    async def list_package_versions(*, image_name, max_pages, on_truncated, **kwargs):
+        return await list_package_versions_(
+            image_name=image_name, http_client=http_client, max_pages=max_pages, on_truncated=on_truncated
+        )
+
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', list_package_versions)
+    await main_(**(main_defaults | {'image_names': 'a,b', 'max_versions_scanned': '150', 'dry_run': 'true'}))
+    captured = capsys.readouterr()
+    assert http_client.requests == 2
+    assert main.versions_scanned == 200
+    assert 'Only fetched the first 2 page(s) of versions for a. Older versions were not considered.\n' in captured.out
+    assert 'Skipping b, since max-versions-scanned was reached\n' in captured.out
+
+
+@pytest.mark.asyncio
+async def test_token_file(mocker, capsys, tmp_path):
+    token_file = tmp_path / 'token'
+    token_file.write_text('ghp_secret\n')
+    mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    mocked_client = mocker.patch.object(main, 'AsyncClient', wraps=AsyncClient)
+    await main_(**(main_defaults | {'token': '', 'token_file': str(token_file), 'print_config': 'true'}))
+    assert mocked_client.call_args.kwargs['headers']['Authorization'] == 'Bearer ghp_secret'
+    assert 'ghp_secret' not in capsys.readouterr().out
+
+    with pytest.raises(ValueError, match='token cannot be combined with token-file'):
+        await main_(**(main_defaults | {'token_file': str(token_file)}))
+    with pytest.raises(ValueError, match='token is required, unless token-file is set'):
+        await main_(**(main_defaults | {'token': ''}))
+
+
+@pytest.mark.asyncio
+async def test_abort_on_secondary_rate_limit(mocker, capsys):
+    mocker.patch.object(main, 'aborted', False)
+    mocker.patch.object(main, 'abort_on_secondary_rate_limit', False)
+    mocker.patch.object(main, 'failed', [])
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(return_value=[PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now())]),
+    )
+    versions = [{'id': i, 'created_at': '2021-05-26T14:03:03Z', 'updated_at': '2021-05-26T14:03:03Z'} for i in range(3)]
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock(return_value=versions))
+    response = Mock()
+    response.is_error = True
+    response.status_code = 403
+    response.json.return_value = {'message': 'You have exceeded a secondary rate limit. Please wait a few minutes.'}
+    mocked_delete = mocker.patch.object(AsyncClient, 'delete', return_value=response)
+
+    kwargs = main_defaults | {'image_names': 'a', 'on_secondary_rate_limit': 'abort', 'parallel_deletes': '1'}
+    with pytest.raises(SystemExit) as e:
+        await main_(**kwargs)
+    assert e.value.code == 1
+    assert mocked_delete.call_count == 1
+    assert main.failed[0].is_secondary_rate_limit
+    assert 'Aborted the run, since the Github API responded that the secondary rate limit was exceeded\n' in (
+        capsys.readouterr().out
+    )
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('halt', ['true', 'false'])
+async def test_halt_on_auth_errors(mocker, capsys, halt):
+    mocker.patch.object(main, 'aborted', False)
+    mocker.patch.object(main, 'consecutive_auth_failures', 0)
+    mocker.patch.object(main, 'failed', [])
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(return_value=[PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now())]),
+    )
+    versions = [{'id': i, 'created_at': '2021-05-26T14:03:03Z', 'updated_at': '2021-05-26T14:03:03Z'} for i in range(8)]
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock(return_value=versions))
+    response = Mock()
+    response.is_error = True
+    response.status_code = 403
+    response.json.return_value = {'message': 'Must have admin rights to Repository.'}
+    mocked_delete = mocker.patch.object(AsyncClient, 'delete', return_value=response)
+
+    kwargs = main_defaults | {'image_names': 'a', 'halt_on_auth_errors': halt, 'parallel_deletes': '1'}
+    if halt == 'false':
+        await main_(**kwargs)
+        assert mocked_delete.call_count == 8
+        return
+    with pytest.raises(SystemExit) as e:
+        await main_(**kwargs)
+    assert e.value.code == 1
+    assert mocked_delete.call_count == 5
+    assert 'Aborted the run, since 5 deletions in a row failed with an auth error\n' in capsys.readouterr().out
+
+
+@pytest.mark.asyncio
+async def test_circuit_breaker_threshold(mocker, capsys):
+    mocker.patch.object(main, 'aborted', False)
+    mocker.patch.object(main, 'consecutive_failures', 0)
+    mocker.patch.object(main, 'deleted', [])
+    mocker.patch.object(main, 'failed', [])
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(return_value=[PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now())]),
+    )
+    versions = [{'id': i, 'created_at': '2021-05-26T14:03:03Z', 'updated_at': '2021-05-26T14:03:03Z'} for i in range(8)]
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock(return_value=versions))
+    ok_response, error_response = Mock(), Mock()
+    ok_response.is_error = False
+    error_response.is_error = True
+    error_response.status_code = 502
+    error_response.json.return_value = {'message': 'Bad gateway'}
+    # The streak is broken by the third deletion, and only reaches the threshold with the seventh
+    responses = [error_response, error_response, ok_response, *[error_response] * 5]
+    mocked_delete = mocker.patch.object(AsyncClient, 'delete', side_effect=responses)
+
+    kwargs = main_defaults | {'image_names': 'a', 'circuit_breaker_threshold': '4', 'parallel_deletes': '1'}
+    with pytest.raises(SystemExit) as e:
+        await main_(**kwargs)
+    assert e.value.code == 1
+    assert mocked_delete.call_count == 7
+    captured = capsys.readouterr()
+    assert '::set-output name=deleted::a:2\n' in captured.out
+    assert 'Aborted the run, since 4 deletions in a row failed\n' in captured.out
+
+
+@pytest.mark.asyncio
+async def test_timing(mocker, capsys):
+    mocker.patch.object(main, 'time_spent', dict.fromkeys(main.RequestPhase, 0.0))
+    mock_list_response = Mock()
+    mock_list_response.json = lambda: [
+        {'id': 1, 'updated_at': '2021-05-26T14:03:03Z', 'name': 'a', 'created_at': '2021-05-26T14:03:03Z'}
+    ]
+    mocker.patch.object(AsyncClient, 'get', return_value=mock_list_response)
+    mocker.patch.object(AsyncClient, 'delete', return_value=mock_response)
+    await main_(**(main_defaults | {'image_names': 'a', 'timing': 'true'}))
+    captured = capsys.readouterr()
+    summary = captured.out[captured.out.index('Time spent per phase, summed across images:\n') :].splitlines()[1:5]
+    assert [line.split(':')[0] for line in summary] == [
+        '\t- list packages',
+        '\t- list versions',
+        '\t- delete',
+        '\t- total run',
+    ]
+    assert all(float(line.split(': ')[1].removesuffix('s')) >= 0 for line in summary)
+    assert main.time_spent[main.RequestPhase.DELETE] > 0
+
+
+@pytest.mark.asyncio
+async def test_confirm(mocker):
+    mocker.patch.dict(main.environ, {'RETENTION_POLICY_CONFIRMATION': 'I-UNDERSTAND'})
+    mocked_list_packages = mocker.patch.object(main, 'list_packages_with_retries', AsyncMock(return_value=[]))
+    with pytest.raises(ValueError, match='Refusing to delete anything, since confirm does not match'):
+        await main_(**main_defaults)
+    with pytest.raises(ValueError, match='Refusing to delete anything, since confirm does not match'):
+        await main_(**(main_defaults | {'confirm': 'I-DO-NOT'}))
+    assert not mocked_list_packages.called
+
+    await main_(**(main_defaults | {'confirm': 'I-UNDERSTAND'}))
+    await main_(**(main_defaults | {'dry_run': 'true'}))
+    assert mocked_list_packages.call_count == 2
+
+
+@pytest.mark.asyncio
+async def test_packages_only(mocker, capsys):
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(
+            return_value=[
+                main.PackageResponse(
+                    id=1, name='b', created_at=datetime(2021, 1, 1), updated_at=datetime(2022, 1, 1), version_count=3
+                ),
+                main.PackageResponse(id=2, name='a', created_at=datetime(2021, 1, 1), updated_at=datetime(2022, 1, 1)),
+                main.PackageResponse(id=3, name='c', created_at=datetime(2021, 1, 1), updated_at=datetime(2022, 1, 1)),
+            ]
+        ),
+    )
+    mocked_list_versions = mocker.patch.object(main, 'list_package_versions', AsyncMock())
+    await main_(**(main_defaults | {'image_names': 'a,b', 'packages_only': 'true'}))
+    assert not mocked_list_versions.called
+    captured = capsys.readouterr()
+    assert captured.out == (
+        'Found 2 package(s):\n'
+        '\t- a: created 2021-01-01 00:00:00, updated 2022-01-01 00:00:00\n'
+        '\t- b: created 2021-01-01 00:00:00, updated 2022-01-01 00:00:00, 3 version(s)\n'
+    )
+
+
+@pytest.mark.asyncio
+async def test_retry_failed_from(mocker, capsys, tmp_path):
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(
+            return_value=[
+                main.PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now()),
+                main.PackageResponse(id=2, name='b', created_at=datetime.now(), updated_at=datetime.now()),
+            ]
+        ),
+    )
+    mocked_list_versions = mocker.patch.object(main.GithubAPI, 'list_package_versions', AsyncMock())
+    mocker.patch.object(main, 'failed', [])
+
+    async def fail(*, image_name, version_id, **kwargs):
+        main.failed.append(main.DeleteFailure(image_name.value, version_id, 500, 'Server error'))
+
+    # The failed output of one run is the input of the next
+    mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock(side_effect=fail))
+    await main_(**(main_defaults | {'image_names': 'a', 'version_ids': '1,2'}))
+    failed_output = capsys.readouterr().out.split('::set-output name=failed::')[1].splitlines()[0]
+    assert failed_output == 'a:1,a:2'
+    failure_file = tmp_path / 'failed.txt'
+    failure_file.write_text(failed_output + '\nb:3\nc:4\n')
+
+    mocked_delete = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+    await main_(**(main_defaults | {'image_names': 'a,b', 'retry_failed_from': str(failure_file)}))
+    assert not mocked_list_versions.called
+    assert [(c.kwargs['image_name'].value, c.kwargs['version_id']) for c in mocked_delete.call_args_list] == [
+        ('a', 1),
+        ('a', 2),
+        ('b', 3),
+    ]
+    assert "Warning: not retrying c:4, since the packages don't match the image-names\n" in capsys.readouterr().out
+
+    failure_file.write_text('a')
+    with pytest.raises(ValidationError, match="Unable to parse 'a'"):
+        await main_(**(main_defaults | {'retry_failed_from': str(failure_file)}))
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('policy', ['ok', 'warn', 'error'])
+async def test_on_empty_account(mocker, capsys, policy):
+    mocker.patch.object(main.GithubAPI, 'list_packages', AsyncMock(return_value=[]))
+    msg = 'Found no container packages for test. Check the account-type and org-name'
+    if policy == 'error':
+        with pytest.raises(ValueError, match=msg):
+            await main_(**(main_defaults | {'on_empty_account': policy}))
+        return
+    await main_(**(main_defaults | {'on_empty_account': policy}))
+    captured = capsys.readouterr()
+    assert (f'Warning: {msg}' in captured.out) is (policy == 'warn')
+
+
+@pytest.mark.asyncio
+async def test_skip_if_updated_within(mocker, capsys):
+    now = datetime.now(timezone.utc)
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=1, name='a', created_at=now, updated_at=now - timedelta(minutes=5)),
+                PackageResponse(id=2, name='b', created_at=now, updated_at=now - timedelta(hours=1)),
+            ]
+        ),
+    )
+    mocked_get_and_delete = mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock())
+    await main_(**(main_defaults | {'image_names': '*', 'skip_if_updated_within': 'PT15M'}))
+    assert [call.args[0].value for call in mocked_get_and_delete.call_args_list] == ['b']
+    assert 'Skipping a, since it was updated within the last 0:15:00' in capsys.readouterr().out
+    with pytest.raises(ValidationError, match='The skip-if-updated-within must be an ISO 8601 duration'):
+        await main_(**(main_defaults | {'skip_if_updated_within': '15 minutes'}))
+
+
+@pytest.mark.asyncio
+async def test_pushed_manifest(mocker, capsys, tmp_path):
+    digest = 'sha256:' + 'a' * 64
+    mocker.patch.object(main, 'protected_digests', set())
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=i, name=name, created_at=datetime.now(), updated_at=datetime.now())
+                for i, name in enumerate(['a', 'b', 'c'])
+            ]
+        ),
+    )
+    mocked_get_and_delete = mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock())
+    pushed_manifest = tmp_path / 'pushed.json'
+    pushed_manifest.write_text(
+        json.dumps([{'package': 'a', 'digest': digest, 'tags': ['v1']}, {'package': 'c', 'digest': 'sha256:c'}])
+    )
+    await main_(**(main_defaults | {'image_names': 'a,b', 'pushed_manifest': str(pushed_manifest)}))
+    assert [call.args[0].value for call in mocked_get_and_delete.call_args_list] == ['a']
+    assert main.protected_digests == {digest, 'sha256:c'}
+    assert 'Protecting 2 image digest(s) from the pushed-manifest' in capsys.readouterr().out
+    assert release_digests_rule({'name': digest}, datetime.now(), _create_inputs_model()) == Decision.PROTECT
+
+    pushed_manifest.write_text(json.dumps({'package': 'a'}))
+    with pytest.raises(ValidationError, match='The pushed-manifest must hold a JSON list'):
+        await main_(**(main_defaults | {'pushed_manifest': str(pushed_manifest)}))
+
+
+@pytest.mark.asyncio
+async def test_packages_are_processed_in_sorted_order(mocker):
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=i, name=name, created_at=datetime.now(), updated_at=datetime.now())
+                for i, name in enumerate(['c', 'a', 'b'])
+            ]
+        ),
+    )
+    mocked_get_and_delete = mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock())
+    await main_(**main_defaults)
+    assert [call.args[0].value for call in mocked_get_and_delete.call_args_list] == ['a', 'b', 'c']
+
+
+@pytest.mark.asyncio
+async def test_missing_updated_at_error_stops_other_images(mocker):
+    mocker.patch.object(
+        main,
+        'list_packages_with_retries',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=i, name=name, created_at=datetime.now(), updated_at=datetime.now())
+                for i, name in enumerate(['a', 'b'])
+            ]
+        ),
+    )
+
+    async def list_versions(*, image_name, **kwargs):
+        # Image b is still listing its versions when image a runs into the error
+        await asyncio.sleep(0.01 if image_name.value == 'b' else 0)
+        updated_at = None if image_name.value == 'a' else '2021-05-26T14:03:03Z'
+        return [{'id': 1, 'created_at': '2021-05-26T14:03:03Z', 'updated_at': updated_at}]
+
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', list_versions)
+    mocked_delete = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+    inputs = {'image_names': 'a,b', 'timestamp_to_use': 'updated_at', 'on_missing_updated_at': 'error'}
+    with pytest.raises(ValueError, match='Image version 1 has no updated_at timestamp'):
+        await main_(**(main_defaults | inputs))
+    await asyncio.sleep(0.02)
+    assert not mocked_delete.called
+
+
+@pytest.mark.asyncio
+async def test_dry_run_output_is_stable(mocker, capsys):
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(
+            return_value=[
+                PackageResponse(id=i, name=name, created_at=datetime.now(), updated_at=datetime.now())
+                for i, name in enumerate(['b', 'a'])
+            ]
+        ),
+    )
+    delays = iter([0.02, 0, 0, 0.02])
+
+    async def list_versions(*, image_name, **kwargs):
+        # Let the images finish listing in a different order on each run
+        await asyncio.sleep(next(delays))
+        return [
+            {'id': i, 'name': f'sha256:{i}', 'created_at': '2021-05-26T14:03:03Z', 'metadata': {}} for i in [3, 1, 2]
+        ]
+
+    mocker.patch.object(main.GithubAPI, 'list_package_versions', list_versions)
+    outputs = []
+    for _ in range(2):
+        mocker.patch.object(main, 'deleted', [])
+        await main_(**(main_defaults | {'image_names': '*', 'dry_run': 'true'}))
+        outputs.append(capsys.readouterr().out)
+    assert outputs[0] == outputs[1]
+    assert [line for line in outputs[0].splitlines() if line.startswith('Would delete')] == [
+        f'Would delete image {name}:{i} (sha256:{i}, untagged)' for name in 'ab' for i in [1, 2, 3]
+    ]
+
+
+@pytest.mark.asyncio
+async def test_dry_run_sets_deleted_output(mocker, capsys):
+    mocker.patch.object(main, 'deleted', [])
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_packages',
+        AsyncMock(return_value=[PackageResponse(id=1, name='a', created_at=datetime.now(), updated_at=datetime.now())]),
+    )
+    mocker.patch.object(
+        main.GithubAPI,
+        'list_package_versions',
+        AsyncMock(return_value=[{'id': 1, 'created_at': '2021-05-26T14:03:03Z', 'metadata': {}}]),
+    )
+    mocked_delete = mocker.patch.object(main.GithubAPI, 'delete_package', AsyncMock())
+    await main_(**(main_defaults | {'image_names': 'a', 'dry_run': 'true'}))
+    assert not mocked_delete.called
+    assert '::set-output name=deleted::a:1\n' in capsys.readouterr().out
+
+
+@pytest.mark.asyncio
+async def test_failures_are_grouped_by_cause(mocker, capsys):
+    mocker.patch.object(main, 'failed', [])
+    mock_list_response = Mock()
+    mock_list_response.json = lambda: [
+        {'id': 1, 'updated_at': '2021-05-26T14:03:03Z', 'name': 'a', 'created_at': '2021-05-26T14:03:03Z'}
+    ]
+    mock_delete_response = Mock()
+    mock_delete_response.is_error = True
+    mock_delete_response.status_code = 429
+    mock_delete_response.json = lambda: {'message': 'Too many requests'}
+
+    mocker.patch.object(AsyncClient, 'get', return_value=mock_list_response)
+    mocker.patch.object(AsyncClient, 'delete', return_value=mock_delete_response)
+    await main_(**(main_defaults | {'image_names': 'a'}))
+    captured = capsys.readouterr()
+
+    assert [(f.package, f.version_id, f.status, f.message) for f in main.failed] == [('a', 1, 429, 'Too many requests')]
+    assert 'Failed to delete 1 image version(s):\n\t- rate-limit: a:1\n' in captured.out
+    assert '::set-output name=failed::a:1\n' in captured.out
+
+
+@pytest.mark.asyncio
+@pytest.mark.parametrize('would_delete', [True, False])
+async def test_dry_run_nonempty_exit_code(mocker, capsys, would_delete):
+    mocker.patch.object(AsyncClient, 'get', return_value=mock_response)
+    mocker.patch.object(main, 'filter_image_names', return_value={ImageName('a', 'a'), ImageName('b', 'b')})
+    mocker.patch.object(main, 'get_and_delete_old_versions', AsyncMock(side_effect=[would_delete, False]))
+    kwargs = main_defaults | {'dry_run': 'true', 'dry_run_nonempty_exit_code': '3'}
+    if would_delete:
+        with pytest.raises(SystemExit) as e:
+            await main_(**kwargs)
+        assert e.value.code == 3
+    else:
+        await main_(**kwargs)
+    captured = capsys.readouterr()
+    assert '::set-output name=deleted::' in captured.out